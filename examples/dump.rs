@@ -26,7 +26,6 @@ use stm32f4xx_hal::spi::Spi;
 use stm32f4xx_hal::stm32 as pac;
 use stm32f4xx_hal::time::{Bps, MegaHertz};
 
-use spi_memory::prelude::*;
 use spi_memory::series25::Flash;
 
 use core::fmt::Write as _;
@@ -87,18 +86,16 @@ fn main() -> ! {
     let id = flash.read_jedec_id().unwrap();
     hprintln!("{:?}", id).ok();
 
-    let mut addr = 0;
-    const BUF: usize = 32;
-    let mut buf = [0; BUF];
-
-    while addr < SIZE_IN_BYTES {
-        flash.read(addr, &mut buf).unwrap();
-        print(&buf, &mut serial);
-
-        addr += BUF as u32;
-    }
+    let mut buf = [0; 32];
+    flash
+        .read_chunks(0, SIZE_IN_BYTES, &mut buf, |_, chunk| {
+            print(chunk, &mut serial)
+        })
+        .unwrap();
 
     hprintln!("DONE").ok();
 
-    loop {}
+    loop {
+        cortex_m::asm::wfi();
+    }
 }
@@ -0,0 +1,244 @@
+//! A small configuration block stored redundantly in two alternating sectors.
+//!
+//! Every [`BootConfig::store`] goes to the sector not holding the newest copy,
+//! so the previous copy stays intact until the new one is complete. Each copy
+//! carries a sequence number and a CRC-32, and [`BootConfig::load`] always
+//! returns the newest valid copy.
+//!
+//! # Format
+//!
+//! | Size  | Contents                                          |
+//! |-------|---------------------------------------------------|
+//! | 4     | Sequence number, little-endian                    |
+//! | 2     | Length of the data, little-endian                 |
+//! | *len* | Data                                              |
+//! | 4     | CRC-32 of all of the above, little-endian         |
+
+use crate::delay::Delay;
+use crate::digest::{Crc32, Digest};
+use crate::geometry::SECTOR_SIZE;
+use crate::series25::Flash;
+use crate::utils::Context;
+use crate::{BlockDevice, Error, Read};
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+
+/// Size of the sequence number and length.
+const HEADER: usize = 6;
+/// Size of the CRC.
+const CRC: usize = 4;
+/// The largest amount of data a copy can hold.
+pub const MAX_LEN: usize = SECTOR_SIZE as usize - HEADER - CRC;
+
+/// A valid copy found in one of the sectors.
+#[derive(Debug, Copy, Clone)]
+struct Slot {
+    index: usize,
+    seq: u32,
+    len: usize,
+}
+
+/// A configuration block kept in two sectors.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BootConfig {
+    sectors: [u32; 2],
+}
+
+impl BootConfig {
+    /// Creates a handle for a configuration block stored in the sectors at
+    /// `first` and `second`.
+    ///
+    /// On chips with sectors larger than 4 KiB, [`BootConfig::store`] fails
+    /// with [`Error::NotAligned`] unless both addresses are aligned to
+    /// [`Flash::sector_size`], so that erasing one copy never touches the
+    /// other.
+    ///
+    /// # Panics
+    ///
+    /// Panics if either address is not on a 4 KiB sector boundary.
+    pub fn new(first: u32, second: u32) -> Self {
+        assert!(
+            (first | second) & (SECTOR_SIZE - 1) == 0,
+            "boot config sectors must be sector-aligned"
+        );
+        Self {
+            sectors: [first, second],
+        }
+    }
+
+    /// Reads the newest valid copy into `buf`, and returns the length of the
+    /// stored data.
+    ///
+    /// Returns `None` if neither sector holds a valid copy. If `buf` is too
+    /// short, only the start of the data is copied.
+    pub fn load<SPI, CS, D>(
+        &self,
+        flash: &mut Flash<'_, SPI, CS, D>,
+        buf: &mut [u8],
+    ) -> Result<Option<usize>, Error<SPI, CS>>
+    where
+        SPI: Transfer<u8>,
+        CS: OutputPin,
+        D: Delay,
+    {
+        let (addr, len) = match self.newest(flash)? {
+            Some(copy) => (self.sectors[copy.index], copy.len),
+            None => return Ok(None),
+        };
+
+        let n = len.min(buf.len());
+        flash
+            .read(addr + HEADER as u32, &mut buf[..n])
+            .context("boot config load", addr)?;
+        Ok(Some(len))
+    }
+
+    /// Stores `data` as the new configuration.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `data` is longer than [`MAX_LEN`].
+    pub fn store<SPI, CS, D>(
+        &self,
+        flash: &mut Flash<'_, SPI, CS, D>,
+        data: &[u8],
+    ) -> Result<(), Error<SPI, CS>>
+    where
+        SPI: Transfer<u8>,
+        CS: OutputPin,
+        D: Delay,
+    {
+        assert!(data.len() <= MAX_LEN, "boot config too large");
+        let [first, second] = self.sectors;
+        if (first | second) & (flash.sector_size() - 1) != 0 {
+            return Err(Error::NotAligned);
+        }
+
+        let (index, seq) = match self.newest(flash)? {
+            Some(copy) => (1 - copy.index, copy.seq.wrapping_add(1)),
+            None => (0, 0),
+        };
+        let addr = self.sectors[index];
+
+        let mut header = [0; HEADER];
+        header[..4].copy_from_slice(&seq.to_le_bytes());
+        header[4..].copy_from_slice(&(data.len() as u16).to_le_bytes());
+        let mut crc = Crc32::new();
+        crc.update(&header);
+        crc.update(data);
+
+        flash
+            .erase_sectors(addr, 1)
+            .context("boot config erase", addr)?;
+        let mut offset = addr;
+        for part in [&header[..], data, &crc.finish().to_le_bytes()[..]].iter() {
+            // `write_bytes` needs a mutable buffer, which `data` isn't.
+            for chunk in part.chunks(64) {
+                let mut buf = [0; 64];
+                buf[..chunk.len()].copy_from_slice(chunk);
+                flash
+                    .write_bytes(offset, &mut buf[..chunk.len()])
+                    .context("boot config store", offset)?;
+                offset += chunk.len() as u32;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds the newest valid copy.
+    fn newest<SPI, CS, D>(
+        &self,
+        flash: &mut Flash<'_, SPI, CS, D>,
+    ) -> Result<Option<Slot>, Error<SPI, CS>>
+    where
+        SPI: Transfer<u8>,
+        CS: OutputPin,
+        D: Delay,
+    {
+        let mut newest = None;
+        for (index, &addr) in self.sectors.iter().enumerate() {
+            let mut header = [0; HEADER];
+            flash
+                .read(addr, &mut header)
+                .context("boot config check", addr)?;
+            let seq = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+            let len = usize::from(u16::from_le_bytes([header[4], header[5]]));
+            if len > MAX_LEN {
+                continue;
+            }
+
+            let mut crc = Crc32::new();
+            flash
+                .digest(addr, (HEADER + len) as u32, &mut crc)
+                .context("boot config check", addr)?;
+            let mut expected = [0; CRC];
+            flash
+                .read(addr + (HEADER + len) as u32, &mut expected)
+                .context("boot config check", addr)?;
+            if crc.finish() != u32::from_le_bytes(expected) {
+                continue;
+            }
+
+            match newest {
+                Some(Slot {
+                    seq: newest_seq, ..
+                }) if newest_seq >= seq => {}
+                _ => newest = Some(Slot { index, seq, len }),
+            }
+        }
+        Ok(newest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Geometry, BLOCK64_SIZE};
+    use crate::mock;
+
+    #[test]
+    fn test_alternating_copies() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        let config = BootConfig::new(0x1000, 0x2000);
+
+        let mut buf = [0; 8];
+        assert_eq!(config.load(&mut flash, &mut buf).unwrap(), None);
+        config.store(&mut flash, b"first").unwrap();
+        config.store(&mut flash, b"second").unwrap();
+        assert_eq!(config.load(&mut flash, &mut buf).unwrap(), Some(6));
+        assert_eq!(&buf[..6], b"second");
+        assert_eq!(chip.borrow().memory[0x1000 + HEADER], b'f');
+        assert_eq!(chip.borrow().memory[0x2000 + HEADER], b's');
+
+        // An interrupted store leaves the previous copy in charge.
+        chip.borrow_mut().memory[0x2000 + HEADER] = 0;
+        assert_eq!(config.load(&mut flash, &mut buf).unwrap(), Some(5));
+        assert_eq!(&buf[..5], b"first");
+        config.store(&mut flash, b"third").unwrap();
+        assert_eq!(chip.borrow().memory[0x2000 + HEADER], b't');
+    }
+
+    #[test]
+    fn test_large_sectors() {
+        let (chip, spi, cs) = mock::chip(0x20000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        flash.set_geometry(Geometry {
+            sector_size: BLOCK64_SIZE,
+            ..Geometry::standard(0x20000)
+        });
+
+        match BootConfig::new(0x1000, 0x2000).store(&mut flash, b"first") {
+            Err(Error::NotAligned) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(chip.borrow().transactions.iter().all(|t| t[0] != 0xD8));
+
+        let config = BootConfig::new(0, 0x10000);
+        config.store(&mut flash, b"first").unwrap();
+        config.store(&mut flash, b"second").unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(config.load(&mut flash, &mut buf).unwrap(), Some(6));
+        assert_eq!(chip.borrow().memory[HEADER], b'f');
+    }
+}
@@ -0,0 +1,219 @@
+//! A small read cache for workloads that keep fetching data from the same
+//! few pages, like font or asset lookups.
+//!
+//! [`ReadCache`] wraps a driver and keeps whole pages in caller-provided
+//! [`CacheLine`]s. Reads are served from the cached pages where possible, and
+//! missing pages are fetched in one read each, going into an empty line or replacing the
+//! oldest one.
+//!
+//! Writes and erases through the cache invalidate the lines they affect. If
+//! the chip is modified in any other way, eg. through [`ReadCache::get_mut`],
+//! [`ReadCache::invalidate`] has to be called afterwards.
+
+use crate::geometry::{align_down, PAGE_SIZE};
+use crate::{BlockDevice, Error, Read};
+use core::fmt;
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+
+/// One page worth of cached memory contents.
+#[derive(Clone)]
+pub struct CacheLine {
+    /// Start address of the cached page, or `None` if the line is empty.
+    addr: Option<u32>,
+    data: [u8; PAGE_SIZE as usize],
+}
+
+impl CacheLine {
+    /// An empty line, for initializing the array of lines.
+    pub const EMPTY: Self = Self {
+        addr: None,
+        data: [0; PAGE_SIZE as usize],
+    };
+}
+
+impl fmt::Debug for CacheLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CacheLine")
+            .field("addr", &self.addr)
+            .finish()
+    }
+}
+
+/// A read cache in front of a memory chip driver.
+#[derive(Debug)]
+pub struct ReadCache<'a, M> {
+    memory: M,
+    lines: &'a mut [CacheLine],
+    /// Index of the line to replace next.
+    next: usize,
+}
+
+impl<'a, M> ReadCache<'a, M> {
+    /// Creates a cache in front of `memory`, keeping up to `lines.len()`
+    /// pages.
+    ///
+    /// All lines start out empty, whatever they contained before. With no
+    /// lines at all, every access goes straight to `memory`.
+    pub fn new(memory: M, lines: &'a mut [CacheLine]) -> Self {
+        let mut this = Self {
+            memory,
+            lines,
+            next: 0,
+        };
+        this.invalidate();
+        this
+    }
+
+    /// Discards all cached pages.
+    pub fn invalidate(&mut self) {
+        self.lines.iter_mut().for_each(|line| line.addr = None);
+    }
+
+    /// Discards the cached pages touching the `len` bytes at `addr`.
+    pub fn invalidate_range(&mut self, addr: u32, len: u32) {
+        let start = align_down(addr, PAGE_SIZE);
+        let end = u64::from(addr) + u64::from(len);
+        for line in self.lines.iter_mut() {
+            if let Some(page) = line.addr {
+                if page >= start && u64::from(page) < end {
+                    line.addr = None;
+                }
+            }
+        }
+    }
+
+    /// Returns a reference to the wrapped driver.
+    pub fn get_ref(&self) -> &M {
+        &self.memory
+    }
+
+    /// Returns a mutable reference to the wrapped driver.
+    ///
+    /// Call [`ReadCache::invalidate`] after modifying the chip through it.
+    pub fn get_mut(&mut self) -> &mut M {
+        &mut self.memory
+    }
+
+    /// Releases the wrapped driver and the cache lines.
+    pub fn release(self) -> (M, &'a mut [CacheLine]) {
+        (self.memory, self.lines)
+    }
+}
+
+impl<M, SPI, CS> Read<u32, SPI, CS> for ReadCache<'_, M>
+where
+    M: Read<u32, SPI, CS>,
+    SPI: Transfer<u8>,
+    CS: OutputPin,
+{
+    /// Reads memory contents into `buf`, starting at `addr`, fetching the
+    /// pages that aren't cached yet.
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<SPI, CS>> {
+        if self.lines.is_empty() {
+            return self.memory.read(addr, buf);
+        }
+
+        let mut done = 0;
+        while done < buf.len() {
+            let current = addr.wrapping_add(done as u32);
+            let page = align_down(current, PAGE_SIZE);
+            let offset = (current - page) as usize;
+            let len = (PAGE_SIZE as usize - offset).min(buf.len() - done);
+
+            let index = match self.lines.iter().position(|line| line.addr == Some(page)) {
+                Some(index) => index,
+                None => {
+                    let index = match self.lines.iter().position(|line| line.addr.is_none()) {
+                        Some(index) => index,
+                        None => {
+                            let index = self.next;
+                            self.next = (self.next + 1) % self.lines.len();
+                            index
+                        }
+                    };
+                    let line = &mut self.lines[index];
+                    line.addr = None;
+                    self.memory.read(page, &mut line.data)?;
+                    line.addr = Some(page);
+                    index
+                }
+            };
+            buf[done..done + len].copy_from_slice(&self.lines[index].data[offset..offset + len]);
+            done += len;
+        }
+        Ok(())
+    }
+}
+
+impl<M, SPI, CS> BlockDevice<u32, SPI, CS> for ReadCache<'_, M>
+where
+    M: BlockDevice<u32, SPI, CS>,
+    SPI: Transfer<u8>,
+    CS: OutputPin,
+{
+    /// Erases sectors through the wrapped driver and discards the whole
+    /// cache, since the sector size isn't known here.
+    fn erase_sectors(&mut self, addr: u32, amount: usize) -> Result<(), Error<SPI, CS>> {
+        if amount > 0 {
+            self.invalidate();
+        }
+        self.memory.erase_sectors(addr, amount)
+    }
+
+    fn erase_all(&mut self) -> Result<(), Error<SPI, CS>> {
+        self.invalidate();
+        self.memory.erase_all()
+    }
+
+    /// Writes `data` through the wrapped driver and discards the cached
+    /// pages it touches.
+    fn write_bytes(&mut self, addr: u32, data: &mut [u8]) -> Result<(), Error<SPI, CS>> {
+        self.invalidate_range(addr, data.len() as u32);
+        self.memory.write_bytes(addr, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use crate::series25::Flash;
+
+    #[test]
+    fn test_read_cache() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        for (i, byte) in chip.borrow_mut().memory.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let flash = Flash::init(spi, cs).unwrap();
+        let mut lines = [CacheLine::EMPTY, CacheLine::EMPTY];
+        let mut cache = ReadCache::new(flash, &mut lines);
+        let reads = |chip: &mock::Chip| chip.opcodes().iter().filter(|&&op| op == 0x03).count();
+
+        let mut buf = [0; 4];
+        cache.read(0x1FE, &mut buf).unwrap();
+        assert_eq!(buf, [0xFE, 0xFF, 0x00, 0x01]);
+        cache.read(0x100, &mut buf).unwrap();
+        cache.read(0x2F0, &mut buf).unwrap();
+        assert_eq!(buf, [0xF0, 0xF1, 0xF2, 0xF3]);
+        assert_eq!(reads(&chip.borrow()), 2);
+
+        // Writes go to the chip and drop the stale page.
+        cache.write_bytes(0x2F0, &mut [0x00]).unwrap();
+        cache.read(0x2F0, &mut buf).unwrap();
+        assert_eq!(buf, [0x00, 0xF1, 0xF2, 0xF3]);
+        assert_eq!(reads(&chip.borrow()), 3);
+
+        // A third page replaces the oldest line.
+        cache.read(0x300, &mut buf).unwrap();
+        cache.read(0x2F0, &mut buf).unwrap();
+        assert_eq!(reads(&chip.borrow()), 4);
+        cache.read(0x100, &mut buf).unwrap();
+        assert_eq!(reads(&chip.borrow()), 5);
+
+        cache.erase_sectors(0, 1).unwrap();
+        cache.read(0x2F0, &mut buf).unwrap();
+        assert_eq!(buf, [0xFF; 4]);
+    }
+}
@@ -0,0 +1,83 @@
+//! Delays for operations that need the chip some time to settle.
+//!
+//! The driver only needs microsecond delays, so instead of depending on one
+//! version of the embedded-hal delay traits, it uses its own [`Delay`] trait.
+//! Every embedded-hal 0.2 `DelayUs<u32>` implements it, and so does an
+//! embedded-hal 1.0 `DelayNs` wrapped in [`Hal1Delay`] (with the
+//! `embedded-hal-1` Cargo feature).
+
+/// A provider of blocking delays.
+pub trait Delay {
+    /// Blocks for at least `us` microseconds.
+    fn delay_us(&mut self, us: u32);
+}
+
+impl<T: embedded_hal::blocking::delay::DelayUs<u32>> Delay for T {
+    fn delay_us(&mut self, us: u32) {
+        embedded_hal::blocking::delay::DelayUs::delay_us(self, us);
+    }
+}
+
+/// The delay type of a driver that has no delay provider.
+///
+/// This type has no values, it only fills in the type parameter until a
+/// delay provider is set.
+#[derive(Debug)]
+pub enum NoDelay {}
+
+impl embedded_hal::blocking::delay::DelayUs<u32> for NoDelay {
+    fn delay_us(&mut self, _us: u32) {
+        match *self {}
+    }
+}
+
+/// Adapts an embedded-hal 1.0 `DelayNs` to [`Delay`].
+///
+/// This is only available with the `embedded-hal-1` Cargo feature.
+#[cfg(feature = "embedded-hal-1")]
+#[derive(Debug)]
+pub struct Hal1Delay<D>(pub D);
+
+#[cfg(feature = "embedded-hal-1")]
+impl<D: embedded_hal_1::delay::DelayNs> Delay for Hal1Delay<D> {
+    fn delay_us(&mut self, us: u32) {
+        self.0.delay_us(us);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Hal02(u32);
+
+    impl embedded_hal::blocking::delay::DelayUs<u32> for Hal02 {
+        fn delay_us(&mut self, us: u32) {
+            self.0 += us;
+        }
+    }
+
+    #[test]
+    fn test_hal02_delay() {
+        let mut hal = Hal02::default();
+        Delay::delay_us(&mut hal, 5);
+        assert_eq!(hal.0, 5);
+    }
+
+    #[cfg(feature = "embedded-hal-1")]
+    #[test]
+    fn test_hal1_delay() {
+        struct Hal1(u32);
+
+        impl embedded_hal_1::delay::DelayNs for Hal1 {
+            fn delay_ns(&mut self, ns: u32) {
+                self.0 += ns;
+            }
+        }
+
+        let mut delay = Hal1Delay(Hal1(0));
+        Delay::delay_us(&mut delay, 5);
+        assert_eq!((delay.0).0, 5000);
+    }
+}
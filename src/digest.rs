@@ -0,0 +1,135 @@
+//! Checksums over chip contents.
+//!
+//! [`Flash::digest`] feeds memory contents into any [`Digest`], so MCUs with a
+//! CRC peripheral can implement the trait on top of their HAL instead of
+//! paying for a software implementation. [`Crc32`] and [`Crc16`] are small
+//! software fallbacks.
+//!
+//! [`Flash::digest`]: crate::series25::Flash::digest
+
+/// A checksum or hash function that consumes data incrementally.
+pub trait Digest {
+    /// The resulting checksum or hash.
+    type Output;
+
+    /// Feeds `data` into the digest.
+    fn update(&mut self, data: &[u8]);
+
+    /// Returns the digest of all data fed in so far.
+    fn finish(&self) -> Self::Output;
+}
+
+/// The CRC-32 used by Ethernet, zlib and many others (CRC-32/ISO-HDLC).
+///
+/// This computes the CRC bit by bit, trading speed for not needing a lookup
+/// table.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    /// Creates a digest that has not seen any data yet.
+    pub fn new() -> Self {
+        Self { crc: 0xFFFF_FFFF }
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Digest for Crc32 {
+    type Output = u32;
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.crc ^= u32::from(byte);
+            for _ in 0..8 {
+                let mask = (self.crc & 1).wrapping_neg();
+                self.crc = (self.crc >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.crc
+    }
+}
+
+/// The CRC-16 used by X.25 framing and many others (CRC-16/CCITT-FALSE).
+///
+/// Like [`Crc32`], this works bit by bit without a lookup table.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Crc16 {
+    crc: u16,
+}
+
+impl Crc16 {
+    /// Creates a digest that has not seen any data yet.
+    pub fn new() -> Self {
+        Self { crc: 0xFFFF }
+    }
+}
+
+impl Default for Crc16 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Digest for Crc16 {
+    type Output = u16;
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.crc ^= u16::from(byte) << 8;
+            for _ in 0..8 {
+                let mask = (self.crc >> 15).wrapping_neg();
+                self.crc = (self.crc << 1) ^ (0x1021 & mask);
+            }
+        }
+    }
+
+    fn finish(&self) -> u16 {
+        self.crc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use crate::series25::Flash;
+
+    #[test]
+    fn test_crc32() {
+        let mut crc = Crc32::new();
+        assert_eq!(crc.finish(), 0);
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_crc16() {
+        let mut crc = Crc16::new();
+        assert_eq!(crc.finish(), 0xFFFF);
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0x29B1);
+    }
+
+    #[test]
+    fn test_flash_digest() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().memory[0x100..0x109].copy_from_slice(b"123456789");
+        let mut flash = Flash::init(spi, cs).unwrap();
+
+        let mut crc = Crc32::new();
+        flash.digest(0x100, 9, &mut crc).unwrap();
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+}
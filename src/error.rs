@@ -25,10 +25,70 @@ pub enum Error<SPI: Transfer<u8>, GPIO: OutputPin> {
     /// still a write in progress).
     UnexpectedStatus,
 
+    /// An access would run past the end of the chip.
+    ///
+    /// This is only reported once the driver knows the chip's capacity.
+    OutOfBounds,
+
+    /// An erase or write would touch a write-protected region of the chip.
+    Protected {
+        /// The first protected address that would have been touched.
+        addr: u32,
+    },
+
+    /// Memory contents read back differ from what was written, or repeated
+    /// reads of the same memory disagree.
+    Mismatch {
+        /// The first address with unexpected contents.
+        addr: u32,
+    },
+
+    /// The status register could not be written because it is locked by the
+    /// SRWD bit and the `/WP` pin.
+    StatusLocked,
+
+    /// An address or length is not aligned to the size the operation works
+    /// in, eg. an erase that doesn't cover whole sectors.
+    NotAligned,
+
+    /// The chip was still busy when the timeout set with
+    /// [`Flash::set_polling`](crate::series25::Flash::set_polling) ran out.
+    Timeout,
+
+    /// An operation started with one of the `start_*` methods of
+    /// [`Flash`](crate::series25::Flash) hasn't been completed with
+    /// [`Flash::poll_complete`](crate::series25::Flash::poll_complete) yet.
+    Busy,
+
+    /// The connected chip couldn't be identified, eg. because there is none
+    /// and the bus reads as all zeros.
+    UnknownChip,
+
     #[doc(hidden)]
     __NonExhaustive(private::Private),
 }
 
+impl<SPI: Transfer<u8>, GPIO: OutputPin> Error<SPI, GPIO> {
+    /// A short description of the kind of error, for logging without
+    /// requiring `Debug` or `Display` on the HAL errors.
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Error::Spi(_) => "SPI error",
+            Error::Gpio(_) => "GPIO error",
+            Error::UnexpectedStatus => "unexpected status",
+            Error::OutOfBounds => "out of bounds",
+            Error::Protected { .. } => "write-protected",
+            Error::Mismatch { .. } => "contents mismatch",
+            Error::StatusLocked => "status register locked",
+            Error::NotAligned => "not aligned",
+            Error::Timeout => "timeout",
+            Error::Busy => "busy",
+            Error::UnknownChip => "unknown chip",
+            Error::__NonExhaustive(_) => unreachable!(),
+        }
+    }
+}
+
 impl<SPI: Transfer<u8>, GPIO: OutputPin> Debug for Error<SPI, GPIO>
 where
     SPI::Error: Debug,
@@ -39,6 +99,14 @@ where
             Error::Spi(spi) => write!(f, "Error::Spi({:?})", spi),
             Error::Gpio(gpio) => write!(f, "Error::Gpio({:?})", gpio),
             Error::UnexpectedStatus => f.write_str("Error::UnexpectedStatus"),
+            Error::OutOfBounds => f.write_str("Error::OutOfBounds"),
+            Error::Protected { addr } => write!(f, "Error::Protected {{ addr: {:#x} }}", addr),
+            Error::Mismatch { addr } => write!(f, "Error::Mismatch {{ addr: {:#x} }}", addr),
+            Error::StatusLocked => f.write_str("Error::StatusLocked"),
+            Error::NotAligned => f.write_str("Error::NotAligned"),
+            Error::Timeout => f.write_str("Error::Timeout"),
+            Error::Busy => f.write_str("Error::Busy"),
+            Error::UnknownChip => f.write_str("Error::UnknownChip"),
             Error::__NonExhaustive(_) => unreachable!(),
         }
     }
@@ -54,6 +122,14 @@ where
             Error::Spi(spi) => write!(f, "SPI error: {}", spi),
             Error::Gpio(gpio) => write!(f, "GPIO error: {}", gpio),
             Error::UnexpectedStatus => f.write_str("unexpected value in status register"),
+            Error::OutOfBounds => f.write_str("address out of bounds"),
+            Error::Protected { addr } => write!(f, "address {:#x} is write-protected", addr),
+            Error::Mismatch { addr } => write!(f, "unexpected contents at address {:#x}", addr),
+            Error::StatusLocked => f.write_str("status register locked by SRWD and /WP"),
+            Error::NotAligned => f.write_str("address or length not aligned"),
+            Error::Timeout => f.write_str("timed out waiting for the chip"),
+            Error::Busy => f.write_str("another operation is still in progress"),
+            Error::UnknownChip => f.write_str("no known chip detected"),
             Error::__NonExhaustive(_) => unreachable!(),
         }
     }
@@ -0,0 +1,172 @@
+//! Sizes of the units 25-series chips are organized in, and address math on
+//! them.
+//!
+//! All functions are `const fn`, so they can also be used to lay out
+//! partitions at compile time. [`Geometry`] describes a particular chip, whose
+//! sectors may be larger than the usual [`SECTOR_SIZE`].
+
+use crate::series25::Identification;
+use crate::sfdp::SfdpParams;
+use core::ops::Range;
+
+/// Size of a page, the most a single Page Program can write.
+pub const PAGE_SIZE: u32 = 256;
+
+/// Size of a sector, the smallest unit that can be erased.
+pub const SECTOR_SIZE: u32 = 4096;
+
+/// Size of a 32 KiB block, erased by
+/// [`Flash::erase_block32`](crate::series25::Flash::erase_block32).
+pub const BLOCK32_SIZE: u32 = 32 * 1024;
+
+/// Size of a 64 KiB block, erased by
+/// [`Flash::erase_block64`](crate::series25::Flash::erase_block64).
+pub const BLOCK64_SIZE: u32 = 64 * 1024;
+
+/// Rounds `addr` down to a multiple of `to`, which must be a power of two.
+pub const fn align_down(addr: u32, to: u32) -> u32 {
+    addr & !(to - 1)
+}
+
+/// Rounds `addr` up to a multiple of `to`, which must be a power of two.
+///
+/// Addresses above the last multiple of `to` wrap around to 0.
+pub const fn align_up(addr: u32, to: u32) -> u32 {
+    addr.wrapping_add(to - 1) & !(to - 1)
+}
+
+/// Returns the number of the page containing `addr`.
+pub const fn page_of(addr: u32) -> u32 {
+    addr / PAGE_SIZE
+}
+
+/// Returns the number of sectors touched by `range`.
+///
+/// Empty ranges touch no sectors.
+pub const fn sectors_in(range: Range<u32>) -> u32 {
+    let span = align_up(range.end, SECTOR_SIZE).wrapping_sub(align_down(range.start, SECTOR_SIZE));
+    span / SECTOR_SIZE * (range.end > range.start) as u32
+}
+
+/// Known chip families: manufacturer, memory type, and sector size.
+///
+/// The capacity is `1 << n` bytes for capacity code `n` in all of these.
+const FAMILIES: &[(u8, u8, u32)] = &[
+    (0xEF, 0x40, SECTOR_SIZE),  // Winbond W25Q
+    (0xEF, 0x60, SECTOR_SIZE),  // Winbond W25Q..W
+    (0xEF, 0x70, SECTOR_SIZE),  // Winbond W25Q..JV-IM
+    (0xC2, 0x20, SECTOR_SIZE),  // Macronix MX25L
+    (0xC8, 0x40, SECTOR_SIZE),  // GigaDevice GD25Q
+    (0x9D, 0x60, SECTOR_SIZE),  // ISSI IS25LP
+    (0x20, 0xBA, SECTOR_SIZE),  // Micron N25Q
+    (0x20, 0x20, BLOCK64_SIZE), // Micron (ST) M25P, no 4 KiB erase
+];
+
+/// The organization of a chip.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Geometry {
+    /// Size of a page, the most a single Page Program can write.
+    pub page_size: u32,
+    /// Size of a sector, the smallest unit that can be erased.
+    pub sector_size: u32,
+    /// Size of the largest block that can be erased with one command.
+    pub block_size: u32,
+    /// Capacity of the chip in bytes.
+    pub capacity: u32,
+}
+
+impl Geometry {
+    /// Returns the geometry of a typical chip with `capacity` bytes: 256 byte
+    /// pages, 4 KiB sectors and 64 KiB blocks.
+    pub const fn standard(capacity: u32) -> Self {
+        Self {
+            page_size: PAGE_SIZE,
+            sector_size: SECTOR_SIZE,
+            block_size: BLOCK64_SIZE,
+            capacity,
+        }
+    }
+
+    /// Looks up the geometry of a chip by its JEDEC ID.
+    ///
+    /// Returns `None` for unknown chips and chips larger than 16 MiB, which
+    /// need 4-byte addresses.
+    pub fn from_jedec_id(id: &Identification) -> Option<Self> {
+        let (memory_type, code) = match id.device_id() {
+            [memory_type, code] => (*memory_type, *code),
+            _ => return None,
+        };
+        if !(0x10..=0x18).contains(&code) {
+            return None;
+        }
+        FAMILIES
+            .iter()
+            .find(|family| family.0 == id.mfr_code() && family.1 == memory_type)
+            .map(|family| Self {
+                sector_size: family.2,
+                ..Self::standard(1 << code)
+            })
+    }
+
+    /// Derives the geometry from the chip's SFDP parameters.
+    ///
+    /// The smallest and largest supported erase of 4, 32 or 64 KiB become the
+    /// sector and block size. The Basic Flash Parameter table fields parsed by
+    /// [`SfdpParams`] don't include the page size, so it is assumed to be
+    /// 256 bytes. Returns `None` for chips larger than 16 MiB or without any
+    /// of those erase sizes.
+    pub fn from_sfdp(params: &SfdpParams) -> Option<Self> {
+        if params.capacity > 1 << 24 {
+            return None;
+        }
+        let mut sizes = params
+            .erase_types
+            .iter()
+            .filter_map(|erase_type| erase_type.map(|erase_type| erase_type.size))
+            .filter(|&size| size == SECTOR_SIZE || size == BLOCK32_SIZE || size == BLOCK64_SIZE);
+        let first = sizes.next()?;
+        let (sector_size, block_size) = sizes.fold((first, first), |(min, max), size| {
+            (min.min(size), max.max(size))
+        });
+        Some(Self {
+            page_size: PAGE_SIZE,
+            sector_size,
+            block_size,
+            capacity: params.capacity as u32,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_geometry() {
+        const SECTORS: u32 = sectors_in(0x800..0x2001);
+        assert_eq!(SECTORS, 3);
+        assert_eq!(sectors_in(0x1000..0x2000), 1);
+        assert_eq!(sectors_in(0x1800..0x1800), 0);
+        assert_eq!(align_down(0x1234, SECTOR_SIZE), 0x1000);
+        assert_eq!(align_up(0x1234, SECTOR_SIZE), 0x2000);
+        assert_eq!(align_up(0x2000, SECTOR_SIZE), 0x2000);
+        assert_eq!(page_of(0x1234), 0x12);
+    }
+
+    #[test]
+    fn test_from_jedec_id() {
+        let id = Identification::from_jedec_id(&[0xEF, 0x40, 0x15]);
+        assert_eq!(
+            Geometry::from_jedec_id(&id),
+            Some(Geometry::standard(2 * 1024 * 1024))
+        );
+        let id = Identification::from_jedec_id(&[0x20, 0x20, 0x14]);
+        assert_eq!(
+            Geometry::from_jedec_id(&id).unwrap().sector_size,
+            BLOCK64_SIZE
+        );
+        let id = Identification::from_jedec_id(&[0x12, 0x34, 0x14]);
+        assert_eq!(Geometry::from_jedec_id(&id), None);
+    }
+}
@@ -1,8 +1,9 @@
 //! An [`embedded-hal`]-based SPI-Flash chip driver.
 //!
 //! This crate aims to be compatible with common families of SPI flash chips.
-//! Currently, reading, writing, erasing 25-series chips is supported, and
-//! support for other chip families (eg. 24-series chips) is planned.
+//! Currently, reading, writing, erasing 25-series chips is supported, as is
+//! 25-series MRAM, and support for other chip families (eg. 24-series chips)
+//! is planned.
 //!
 //! Contributions are welcome!
 //!
@@ -10,13 +11,37 @@
 
 #![doc(html_root_url = "https://docs.rs/spi-memory/0.2.0")]
 #![warn(missing_debug_implementations, rust_2018_idioms)]
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
 
 #[macro_use]
 mod log;
+pub mod boot_config;
+pub mod cache;
+pub mod delay;
+pub mod digest;
 mod error;
+pub mod geometry;
+#[cfg(feature = "linux")]
+pub mod linux;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(test)]
+mod mock;
+pub mod mram;
 pub mod prelude;
+pub mod probe;
+pub mod scrub;
 pub mod series25;
+pub mod sfdp;
+pub mod shadow;
+#[cfg(feature = "std")]
+pub mod snapshot;
+#[cfg(feature = "embedded-storage")]
+pub mod storage;
+pub mod tlv;
 mod utils;
 
 pub use crate::error::Error;
@@ -31,7 +56,40 @@ pub trait Read<Addr, SPI: Transfer<u8>, CS: OutputPin> {
     /// # Parameters
     /// * `addr`: The address to start reading at.
     /// * `buf`: The buffer to read `buf.len()` bytes into.
+    ///
+    /// Reading into an empty `buf` does nothing and succeeds.
     fn read(&mut self, addr: Addr, buf: &mut [u8]) -> Result<(), Error<SPI, CS>>;
+
+    /// Reads a little-endian `u32` stored at `addr`.
+    fn read_u32_le(&mut self, addr: Addr) -> Result<u32, Error<SPI, CS>> {
+        let mut buf = [0; 4];
+        self.read(addr, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    /// Reads `len` bytes starting at `addr` into a newly allocated `Vec`.
+    ///
+    /// This is only available with the `alloc` Cargo feature.
+    #[cfg(feature = "alloc")]
+    fn read_to_vec(
+        &mut self,
+        addr: Addr,
+        len: usize,
+    ) -> Result<alloc::vec::Vec<u8>, Error<SPI, CS>> {
+        let mut buf = alloc::vec![0; len];
+        self.read(addr, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Reads a value of type `T` stored at `addr`, byte for byte.
+    ///
+    /// This is only available with the `bytemuck` Cargo feature.
+    #[cfg(feature = "bytemuck")]
+    fn read_struct<T: bytemuck::Pod>(&mut self, addr: Addr) -> Result<T, Error<SPI, CS>> {
+        let mut value = T::zeroed();
+        self.read(addr, bytemuck::bytes_of_mut(&mut value))?;
+        Ok(value)
+    }
 }
 
 /// A trait for writing and erasing operations on a memory chip.
@@ -41,6 +99,7 @@ pub trait BlockDevice<Addr, SPI: Transfer<u8>, CS: OutputPin> {
     /// # Parameters
     /// * `addr`: The address to start erasing at. If the address is not on a sector boundary,
     ///   the lower bits can be ignored in order to make it fit.
+    /// * `amount`: The number of sectors to erase. Erasing 0 sectors does nothing and succeeds.
     fn erase_sectors(&mut self, addr: Addr, amount: usize) -> Result<(), Error<SPI, CS>>;
 
     /// Erases the memory chip fully.
@@ -55,5 +114,7 @@ pub trait BlockDevice<Addr, SPI: Transfer<u8>, CS: OutputPin> {
     /// # Parameters
     /// * `addr`: The address to write to.
     /// * `data`: The bytes to write to `addr`.
+    ///
+    /// Writing an empty `data` does nothing and succeeds.
     fn write_bytes(&mut self, addr: Addr, data: &mut [u8]) -> Result<(), Error<SPI, CS>>;
 }
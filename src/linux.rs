@@ -0,0 +1,46 @@
+//! Opening chips attached to a Linux SPI device, eg. on a Raspberry Pi.
+//!
+//! This module is only available with the `linux` Cargo feature.
+
+use crate::series25::Flash;
+use linux_embedded_hal::spidev::{SpiModeFlags, SpidevOptions};
+use linux_embedded_hal::sysfs_gpio::Direction;
+use linux_embedded_hal::{Spidev, SysfsPin};
+use std::io;
+use std::path::Path;
+
+/// A 25-series Flash driver on a Linux spidev device and a sysfs GPIO.
+pub type SpidevFlash<'a> = Flash<'a, Spidev, SysfsPin>;
+
+impl Flash<'_, Spidev, SysfsPin> {
+    /// Opens the chip attached to the spidev device at `path`, using GPIO
+    /// number `cs_gpio` as its chip-select line.
+    ///
+    /// The device is configured for SPI mode 0 at `hz`. The chip's `\CS` pin
+    /// has to be wired to the GPIO, not to the controller's own chip-select,
+    /// because the driver keeps the chip selected across several transfers.
+    ///
+    /// Errors from [`Flash::init`] are turned into [`io::Error`]s.
+    pub fn open_spidev<P: AsRef<Path>>(path: P, cs_gpio: u64, hz: u32) -> io::Result<Self> {
+        let mut spi = Spidev::open(path)?;
+        let options = SpidevOptions::new()
+            .bits_per_word(8)
+            .max_speed_hz(hz)
+            .mode(SpiModeFlags::SPI_MODE_0)
+            .build();
+        spi.0.configure(&options)?;
+
+        let cs = SysfsPin::new(cs_gpio);
+        cs.export().map_err(other)?;
+        cs.set_direction(Direction::High).map_err(other)?;
+
+        Flash::init(spi, cs).map_err(other)
+    }
+}
+
+/// Wraps a GPIO or driver error in an [`io::Error`].
+// `io::Error::other` needs Rust 1.74.
+#[allow(clippy::io_other_error)]
+fn other<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
@@ -1,51 +1,84 @@
+//! Logging facade used throughout the crate.
+//!
+//! The macros forward to `defmt` with the `defmt` feature, to `log` with the
+//! `log` feature, and compile to nothing otherwise. If both features are
+//! enabled, `defmt` is used. Log statements have to work with either crate:
+//! format strings must only use syntax both understand, like `{:?}` and
+//! `{:#x}`, and logged values need both `Debug` and `defmt::Format`.
+
 #![allow(unused_macros)]
 
-#[cfg(feature = "log")]
+#[cfg(feature = "defmt")]
+macro_rules! error {
+    ($($t:tt)*) => {{ defmt::error!($($t)*); }};
+}
+
+#[cfg(feature = "defmt")]
+macro_rules! warn {
+    ($($t:tt)*) => {{ defmt::warn!($($t)*); }};
+}
+
+#[cfg(feature = "defmt")]
+macro_rules! info {
+    ($($t:tt)*) => {{ defmt::info!($($t)*); }};
+}
+
+#[cfg(feature = "defmt")]
+macro_rules! debug {
+    ($($t:tt)*) => {{ defmt::debug!($($t)*); }};
+}
+
+#[cfg(feature = "defmt")]
+macro_rules! trace {
+    ($($t:tt)*) => {{ defmt::trace!($($t)*); }};
+}
+
+#[cfg(all(feature = "log", not(feature = "defmt")))]
 macro_rules! error {
     ($($t:tt)*) => {{ log::error!($($t)*); }};
 }
 
-#[cfg(feature = "log")]
+#[cfg(all(feature = "log", not(feature = "defmt")))]
 macro_rules! warn {
     ($($t:tt)*) => {{ log::warn!($($t)*); }};
 }
 
-#[cfg(feature = "log")]
+#[cfg(all(feature = "log", not(feature = "defmt")))]
 macro_rules! info {
     ($($t:tt)*) => {{ log::info!($($t)*); }};
 }
 
-#[cfg(feature = "log")]
+#[cfg(all(feature = "log", not(feature = "defmt")))]
 macro_rules! debug {
     ($($t:tt)*) => {{ log::debug!($($t)*); }};
 }
 
-#[cfg(feature = "log")]
+#[cfg(all(feature = "log", not(feature = "defmt")))]
 macro_rules! trace {
     ($($t:tt)*) => {{ log::trace!($($t)*); }};
 }
 
-#[cfg(not(feature = "log"))]
+#[cfg(not(any(feature = "log", feature = "defmt")))]
 macro_rules! error {
     ($($t:tt)*) => {{ format_args!($($t)*); }};
 }
 
-#[cfg(not(feature = "log"))]
+#[cfg(not(any(feature = "log", feature = "defmt")))]
 macro_rules! warn {
     ($($t:tt)*) => {{ format_args!($($t)*); }};
 }
 
-#[cfg(not(feature = "log"))]
+#[cfg(not(any(feature = "log", feature = "defmt")))]
 macro_rules! info {
     ($($t:tt)*) => {{ format_args!($($t)*); }};
 }
 
-#[cfg(not(feature = "log"))]
+#[cfg(not(any(feature = "log", feature = "defmt")))]
 macro_rules! debug {
     ($($t:tt)*) => {{ format_args!($($t)*); }};
 }
 
-#[cfg(not(feature = "log"))]
+#[cfg(not(any(feature = "log", feature = "defmt")))]
 macro_rules! trace {
     ($($t:tt)*) => {{ format_args!($($t)*); }};
 }
@@ -0,0 +1,146 @@
+//! Timing statistics of flash operations, for spotting aging chips.
+//!
+//! Erase and program times of Flash chips grow as they wear out. With a
+//! [`Clock`] set up via [`Flash::set_clock`], the 25-series driver records how
+//! long each page program and sector erase took, which firmware can compare
+//! against the datasheet's typical and maximum times.
+//!
+//! This module is only available with the `metrics` Cargo feature.
+//!
+//! [`Flash::set_clock`]: crate::series25::Flash::set_clock
+
+use core::fmt::Debug;
+
+/// A monotonic time source.
+pub trait Clock: Debug {
+    /// Returns the current time in microseconds.
+    ///
+    /// The starting point doesn't matter, only differences between two calls
+    /// are used.
+    fn now_micros(&self) -> u64;
+}
+
+/// Minimum, average and maximum duration of one kind of operation.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Stats {
+    count: u32,
+    total: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Stats {
+    /// Adds an operation that took `micros` microseconds.
+    pub fn record(&mut self, micros: u64) {
+        if self.count == 0 || micros < self.min {
+            self.min = micros;
+        }
+        self.max = self.max.max(micros);
+        self.total += micros;
+        self.count += 1;
+    }
+
+    /// The number of recorded operations.
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// The shortest recorded duration in microseconds.
+    pub fn min(&self) -> Option<u64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.min)
+        }
+    }
+
+    /// The average recorded duration in microseconds.
+    pub fn average(&self) -> Option<u64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.total / u64::from(self.count))
+        }
+    }
+
+    /// The longest recorded duration in microseconds.
+    pub fn max(&self) -> Option<u64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.max)
+        }
+    }
+}
+
+/// Timing statistics collected by a driver.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Timings {
+    /// Durations of single page programs, including status polling.
+    pub page_program: Stats,
+    /// Durations of single sector erases, including status polling.
+    pub sector_erase: Stats,
+    /// Durations of single 32 or 64 KiB block erases, including status
+    /// polling.
+    pub block_erase: Stats,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use crate::prelude::*;
+    use crate::series25::Flash;
+    use core::cell::Cell;
+
+    /// A clock that advances by 10 µs whenever it is read.
+    #[derive(Debug)]
+    struct Ticks(Cell<u64>);
+
+    impl Clock for Ticks {
+        fn now_micros(&self) -> u64 {
+            let now = self.0.get();
+            self.0.set(now + 10);
+            now
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut stats = Stats::default();
+        assert_eq!(stats.average(), None);
+
+        stats.record(30);
+        stats.record(10);
+        stats.record(20);
+        assert_eq!(stats.count(), 3);
+        assert_eq!(stats.min(), Some(10));
+        assert_eq!(stats.average(), Some(20));
+        assert_eq!(stats.max(), Some(30));
+    }
+
+    #[test]
+    fn test_flash_timings() {
+        let clock = Ticks(Cell::new(0));
+        let (_chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        flash.set_clock(&clock);
+
+        flash.write_bytes(0, &mut [0; 300]).unwrap();
+        flash.erase_sectors(0, 1).unwrap();
+        let timings = *flash.timings();
+        assert_eq!(timings.page_program.count(), 2);
+        assert_eq!(timings.page_program.average(), Some(10));
+        assert_eq!(timings.sector_erase.count(), 1);
+        let micros = flash.last_erase_duration().unwrap().micros;
+        assert_eq!(micros, timings.sector_erase.max());
+
+        flash.reset_timings();
+        assert_eq!(*flash.timings(), Timings::default());
+
+        // Every page program reads the clock twice.
+        let report = flash.self_test(0).unwrap();
+        assert_eq!(report.program_micros, Some(33 * 10));
+        assert_eq!(report.verify_micros, Some(10));
+    }
+}
@@ -0,0 +1,321 @@
+//! An in-memory 25-series flash chip for driver unit tests.
+//!
+//! [`Spi`] and [`Cs`] share one [`Chip`], so the emulator can see where a
+//! transaction starts and ends, just like a real chip does.
+
+#![allow(dead_code)] // not every test uses every helper
+
+use core::convert::Infallible;
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::{OutputPin, StatefulOutputPin};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Status register bits, mirroring `series25::Status`.
+const BUSY: u8 = 1 << 0;
+const WEL: u8 = 1 << 1;
+const SRWD: u8 = 1 << 7;
+
+/// Discards `defmt` output, so the tests link with the `defmt` feature.
+#[cfg(feature = "defmt")]
+#[defmt::global_logger]
+struct Logger;
+
+#[cfg(feature = "defmt")]
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+#[cfg(feature = "defmt")]
+defmt::timestamp!("");
+
+#[derive(Debug)]
+pub struct Chip {
+    /// Array contents.
+    pub memory: Vec<u8>,
+    /// Status register.
+    pub status: u8,
+    /// Status register 2, returned by 0x35.
+    pub status2: u8,
+    /// Status register 3, returned by 0x15.
+    pub status3: u8,
+    /// JEDEC ID returned by 0x9F.
+    pub jedec_id: [u8; 3],
+    /// Legacy device ID returned by 0xAB, and after the manufacturer ID by
+    /// 0x90.
+    pub device_id: u8,
+    /// Unique ID returned by 0x4B after 4 dummy bytes.
+    pub unique_id: [u8; 8],
+    /// Number of upcoming 0x03 reads that return their first byte with bit 0
+    /// flipped, like a noisy bus would.
+    pub flip_reads: u32,
+    /// Number of dummy bytes 0x0B expects after the address.
+    pub fast_read_dummy: usize,
+    /// SFDP data returned by 0x5A, `0xFF` past the end.
+    pub sfdp: Vec<u8>,
+    /// Whether the chip is in deep power-down.
+    pub powered_down: bool,
+    /// Number of status reads that report BUSY after an erase or program.
+    pub busy_polls: u32,
+    /// Keep WEL set after an erase or program completes.
+    pub sticky_wel: bool,
+    /// Ignore Write Enable commands.
+    pub ignore_write_enable: bool,
+    /// Ignore erase and program commands, as if the whole array was
+    /// write-protected.
+    pub protected: bool,
+    /// Whether the `/WP` pin is driven low.
+    pub wp_low: bool,
+    /// Behave like MRAM: 0x02 overwrites memory without wrapping at page
+    /// boundaries, and writes complete immediately.
+    pub mram: bool,
+    /// Every completed transaction, as the bytes sent by the master.
+    pub transactions: Vec<Vec<u8>>,
+    selected: bool,
+    busy_left: u32,
+    mosi: Vec<u8>,
+}
+
+impl Chip {
+    pub fn new(size: usize) -> Self {
+        Self {
+            memory: vec![0xFF; size],
+            status: 0,
+            status2: 0,
+            status3: 0,
+            jedec_id: [0xEF, 0x40, 0x14],
+            device_id: 0x13,
+            unique_id: [0xD1, 0x65, 0x38, 0x70, 0x2B, 0x4C, 0x5A, 0x21],
+            flip_reads: 0,
+            fast_read_dummy: 1,
+            sfdp: Vec::new(),
+            powered_down: false,
+            busy_polls: 1,
+            sticky_wel: false,
+            ignore_write_enable: false,
+            protected: false,
+            wp_low: false,
+            mram: false,
+            transactions: Vec::new(),
+            selected: false,
+            busy_left: 0,
+            mosi: Vec::new(),
+        }
+    }
+
+    /// Opcodes of all completed transactions.
+    pub fn opcodes(&self) -> Vec<u8> {
+        self.transactions.iter().map(|t| t[0]).collect()
+    }
+
+    fn addr(&self, m: &[u8]) -> usize {
+        ((m[1] as usize) << 16 | (m[2] as usize) << 8 | m[3] as usize) % self.memory.len()
+    }
+
+    /// Returns the MISO byte for the MOSI byte just received at `idx`.
+    fn respond(&mut self, idx: usize) -> u8 {
+        if self.powered_down && self.mosi[0] != 0xAB {
+            return 0xFF;
+        }
+        match self.mosi[0] {
+            0xAB if idx >= 4 => self.device_id,
+            0x90 if idx == 4 => self.jedec_id[0],
+            0x90 if idx >= 5 => self.device_id,
+            0x4B if idx >= 5 => *self.unique_id.get(idx - 5).unwrap_or(&0),
+            0x35 if idx >= 1 => self.status2,
+            0x15 if idx >= 1 => self.status3,
+            0x9F if idx >= 1 => *self.jedec_id.get(idx - 1).unwrap_or(&0),
+            0x05 if idx >= 1 => {
+                let status = self.status;
+                if self.busy_left > 0 {
+                    self.busy_left -= 1;
+                    if self.busy_left == 0 {
+                        self.status &= !BUSY;
+                        self.clear_wel();
+                    }
+                }
+                status
+            }
+            0x5A if idx >= 5 => {
+                let addr = self.mosi[1..4].iter().fold(0, |a, &b| a << 8 | b as usize);
+                *self.sfdp.get(addr + idx - 5).unwrap_or(&0xFF)
+            }
+            0x03 if idx >= 4 => {
+                let len = self.memory.len();
+                let byte = self.memory[(self.addr(&self.mosi) + idx - 4) % len];
+                if idx == 4 && self.flip_reads > 0 {
+                    self.flip_reads -= 1;
+                    byte ^ 1
+                } else {
+                    byte
+                }
+            }
+            0x0B if idx >= 4 + self.fast_read_dummy => {
+                let len = self.memory.len();
+                self.memory[(self.addr(&self.mosi) + idx - 4 - self.fast_read_dummy) % len]
+            }
+            0x02 if idx >= 4 && self.status & WEL != 0 && !self.protected && self.mram => {
+                let len = self.memory.len();
+                let addr = (self.addr(&self.mosi) + idx - 4) % len;
+                self.memory[addr] = self.mosi[idx];
+                0
+            }
+            0x02 if idx >= 4 && self.status & WEL != 0 && !self.protected => {
+                let base = self.addr(&self.mosi);
+                let page = base & !0xFF;
+                let offset = (base + idx - 4) & 0xFF;
+                self.memory[page + offset] &= self.mosi[idx];
+                0
+            }
+            _ => 0,
+        }
+    }
+
+    fn finish(&mut self) {
+        // `mem::take` needs Rust 1.40.
+        #[allow(clippy::mem_replace_with_default)]
+        let mosi = core::mem::replace(&mut self.mosi, Vec::new());
+        if mosi.is_empty() {
+            return;
+        }
+        if self.powered_down {
+            if mosi[0] == 0xAB {
+                self.powered_down = false;
+            }
+            self.transactions.push(mosi);
+            return;
+        }
+        let writable = self.status & WEL != 0 && self.status & BUSY == 0 && !self.protected;
+        match mosi[0] {
+            0xB9 => self.powered_down = true,
+            0x06 if !self.ignore_write_enable => self.status |= WEL,
+            0x04 => self.status &= !WEL,
+            // SRWD only locks the register while /WP is low.
+            0x01 if mosi.len() > 1
+                && self.status & WEL != 0
+                && self.status & BUSY == 0
+                && !(self.status & SRWD != 0 && self.wp_low) =>
+            {
+                self.status = self.status & (BUSY | WEL) | mosi[1] & !(BUSY | WEL);
+                self.start_busy();
+            }
+            0x31 | 0x11 if mosi.len() > 1 && self.status & WEL != 0 && self.status & BUSY == 0 => {
+                match mosi[0] {
+                    0x31 => self.status2 = mosi[1],
+                    _ => self.status3 = mosi[1],
+                }
+                self.start_busy();
+            }
+            0x02 if writable => self.start_busy(),
+            0x20 | 0x52 | 0xD8 if writable => {
+                let size = match mosi[0] {
+                    0x20 => 0x1000,
+                    0x52 => 0x8000,
+                    _ => 0x10000,
+                };
+                let start = self.addr(&mosi) & !(size - 1);
+                self.memory[start..start + size]
+                    .iter_mut()
+                    .for_each(|b| *b = 0xFF);
+                self.start_busy();
+            }
+            0xC7 if writable => {
+                self.memory.iter_mut().for_each(|b| *b = 0xFF);
+                self.start_busy();
+            }
+            _ => {}
+        }
+        self.transactions.push(mosi);
+    }
+
+    fn clear_wel(&mut self) {
+        if !self.sticky_wel {
+            self.status &= !WEL;
+        }
+    }
+
+    fn start_busy(&mut self) {
+        if self.busy_polls == 0 || self.mram {
+            self.clear_wel();
+        } else {
+            self.status |= BUSY;
+            self.busy_left = self.busy_polls;
+        }
+    }
+}
+
+/// Creates a chip of `size` bytes and the SPI and CS handles attached to it.
+pub fn chip(size: usize) -> (Rc<RefCell<Chip>>, Spi, Cs) {
+    let chip = Rc::new(RefCell::new(Chip::new(size)));
+    (chip.clone(), Spi(chip.clone()), Cs(chip))
+}
+
+#[derive(Debug)]
+pub struct Spi(pub Rc<RefCell<Chip>>);
+
+impl Transfer<u8> for Spi {
+    type Error = Infallible;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+        let mut chip = self.0.borrow_mut();
+        assert!(chip.selected, "SPI transfer without CS asserted");
+        for word in words.iter_mut() {
+            chip.mosi.push(*word);
+            let idx = chip.mosi.len() - 1;
+            *word = chip.respond(idx);
+        }
+        Ok(words)
+    }
+}
+
+/// The `/WP` pin of the chip.
+#[derive(Debug)]
+pub struct Wp(pub Rc<RefCell<Chip>>);
+
+impl OutputPin for Wp {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.0.borrow_mut().wp_low = true;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        self.0.borrow_mut().wp_low = false;
+        Ok(())
+    }
+}
+
+impl StatefulOutputPin for Wp {
+    fn is_set_high(&self) -> Result<bool, Infallible> {
+        Ok(!self.0.borrow().wp_low)
+    }
+
+    fn is_set_low(&self) -> Result<bool, Infallible> {
+        Ok(self.0.borrow().wp_low)
+    }
+}
+
+#[derive(Debug)]
+pub struct Cs(pub Rc<RefCell<Chip>>);
+
+impl OutputPin for Cs {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.0.borrow_mut().selected = true;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        let mut chip = self.0.borrow_mut();
+        if chip.selected {
+            chip.selected = false;
+            chip.finish();
+        }
+        Ok(())
+    }
+}
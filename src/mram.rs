@@ -0,0 +1,290 @@
+//! Driver for 25-series SPI MRAM chips, such as the Everspin MR25H series.
+//!
+//! MRAM uses the same basic command set as 25-series Flash, but writes take
+//! effect immediately, there is no page size limit, and memory never needs to
+//! be erased. Erasing is still supported, by writing `0xFF` bytes, so that
+//! MRAM can stand in for Flash.
+
+use crate::geometry::SECTOR_SIZE;
+use crate::series25::{Protection, Status};
+use crate::utils::{framed_command, spi_command};
+use crate::{BlockDevice, Error, Read};
+use core::convert::TryInto;
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+
+enum Opcode {
+    /// Set the write enable latch.
+    WriteEnable = 0x06,
+    /// Clear the write enable latch.
+    WriteDisable = 0x04,
+    /// Read the 8-bit status register.
+    ReadStatus = 0x05,
+    /// Write the 8-bit status register.
+    WriteStatus = 0x01,
+    Read = 0x03,
+    Write = 0x02,
+}
+
+/// Driver for 25-series SPI MRAM chips.
+///
+/// # Type Parameters
+///
+/// * **`SPI`**: The SPI master to which the MRAM chip is attached.
+/// * **`CS`**: The **C**hip-**S**elect line attached to the `\CS` pin of the
+///   MRAM chip.
+#[derive(Debug)]
+pub struct Mram<SPI: Transfer<u8>, CS: OutputPin> {
+    spi: SPI,
+    cs: CS,
+    capacity: Option<u32>,
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> Mram<SPI, CS> {
+    /// Creates a new MRAM driver.
+    ///
+    /// # Parameters
+    ///
+    /// * **`spi`**: An SPI master. Must be configured to operate in the correct
+    ///   mode for the device.
+    /// * **`cs`**: The **C**hip-**S**elect Pin connected to the `\CS` pin of
+    ///   the MRAM chip. Will be driven low when accessing the device.
+    pub fn init(spi: SPI, cs: CS) -> Result<Self, Error<SPI, CS>> {
+        let mut this = Self {
+            spi,
+            cs,
+            capacity: None,
+        };
+        let status = this.read_status()?;
+        info!("Mram::init: status = {:?}", status);
+
+        // MRAM is never busy, so a set latch is the only thing to look out for.
+        if status.contains(Status::WEL) {
+            return Err(Error::UnexpectedStatus);
+        }
+
+        Ok(this)
+    }
+
+    /// Releases the SPI master and the chip-select pin, consuming the driver.
+    pub fn release(self) -> (SPI, CS) {
+        (self.spi, self.cs)
+    }
+
+    /// Tells the driver the capacity of the chip in bytes.
+    ///
+    /// Erases past the end of the chip then fail with
+    /// [`Error::OutOfBounds`], and [`BlockDevice::erase_all`] needs the
+    /// capacity to know how much to erase.
+    pub fn set_capacity(&mut self, capacity: u32) {
+        self.capacity = Some(capacity);
+    }
+
+    /// Returns the capacity of the chip in bytes, if known.
+    pub fn capacity(&self) -> Option<u32> {
+        self.capacity
+    }
+
+    fn command(&mut self, bytes: &mut [u8]) -> Result<(), Error<SPI, CS>> {
+        spi_command(&mut self.spi, &mut self.cs, None, &mut [bytes])
+    }
+
+    /// Reads the status register.
+    pub fn read_status(&mut self) -> Result<Status, Error<SPI, CS>> {
+        let mut buf = [Opcode::ReadStatus as u8, 0];
+        self.command(&mut buf)?;
+
+        Ok(Status::from_bits_truncate(buf[1]))
+    }
+
+    /// Writes the `PROT` and `SRWD` bits of `status` to the status register
+    /// and checks that they took effect.
+    ///
+    /// Fails with [`Error::StatusLocked`] if SRWD was set and the chip
+    /// ignored the write, most likely because `/WP` is low, and with
+    /// [`Error::UnexpectedStatus`] on other mismatches.
+    pub fn write_status(&mut self, status: Status) -> Result<(), Error<SPI, CS>> {
+        let mask = Status::PROT | Status::SRWD;
+        let locked = self.read_status()?.contains(Status::SRWD);
+
+        self.write_enable()?;
+        let mut cmd_buf = [Opcode::WriteStatus as u8, (status & mask).bits()];
+        self.command(&mut cmd_buf)?;
+
+        if self.read_status()? & mask == status & mask {
+            Ok(())
+        } else if locked {
+            // The chip ignored the command, so the latch is still set.
+            let mut cmd_buf = [Opcode::WriteDisable as u8];
+            self.command(&mut cmd_buf)?;
+            Err(Error::StatusLocked)
+        } else {
+            Err(Error::UnexpectedStatus)
+        }
+    }
+
+    /// Reads the block protection settings from the status register.
+    pub fn get_protection(&mut self) -> Result<Protection, Error<SPI, CS>> {
+        Ok(self.read_status()?.into())
+    }
+
+    /// Writes the block protection settings to the status register, like
+    /// [`Mram::write_status`].
+    ///
+    /// The MR25H series has 2 block protection bits: a `bp` of 1 protects
+    /// the upper quarter of the chip, 2 the upper half and 3 all of it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `protection.bp` is larger than 3.
+    pub fn set_protection(&mut self, protection: Protection) -> Result<(), Error<SPI, CS>> {
+        assert!(protection.bp <= 3, "BP value out of range");
+        self.write_status(protection.status())
+    }
+
+    fn write_enable(&mut self) -> Result<(), Error<SPI, CS>> {
+        let mut cmd_buf = [Opcode::WriteEnable as u8];
+        self.command(&mut cmd_buf)?;
+        Ok(())
+    }
+
+    /// Overwrites the `len` bytes at `addr` with `0xFF`.
+    fn fill_erased(&mut self, addr: u32, len: u32) -> Result<(), Error<SPI, CS>> {
+        if let Some(capacity) = self.capacity {
+            if u64::from(addr) + u64::from(len) > u64::from(capacity) {
+                return Err(Error::OutOfBounds);
+            }
+        }
+
+        let mut buf = [0xFF; 256];
+        let mut done = 0;
+        while done < len {
+            let chunk = (len - done).min(buf.len() as u32);
+            // The transfer replaces the buffer contents with what it received.
+            buf.iter_mut().for_each(|byte| *byte = 0xFF);
+            self.write_bytes(addr + done, &mut buf[..chunk as usize])?;
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> Read<u32, SPI, CS> for Mram<SPI, CS> {
+    /// Reads memory contents into `buf`, starting at `addr`.
+    ///
+    /// Like with 25-series Flash, only 24 bits of `addr` are transferred to the
+    /// device, and the chip only decodes as many of them as it needs.
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<SPI, CS>> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let (spi, cs) = (&mut self.spi, &mut self.cs);
+        framed_command(spi, cs, None, Opcode::Read as u8, Some(addr), 0, buf)
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> BlockDevice<u32, SPI, CS> for Mram<SPI, CS> {
+    /// Fills `amount` 4 KiB sectors, starting with the one containing
+    /// `addr`, with `0xFF`, like erasing them on a Flash chip would.
+    fn erase_sectors(&mut self, addr: u32, amount: usize) -> Result<(), Error<SPI, CS>> {
+        let len = (amount as u64 * u64::from(SECTOR_SIZE))
+            .try_into()
+            .map_err(|_| Error::OutOfBounds)?;
+        self.fill_erased(addr & !(SECTOR_SIZE - 1), len)
+    }
+
+    /// Fills the whole chip with `0xFF`.
+    ///
+    /// Fails with [`Error::OutOfBounds`] unless the capacity was set with
+    /// [`Mram::set_capacity`].
+    fn erase_all(&mut self) -> Result<(), Error<SPI, CS>> {
+        match self.capacity {
+            Some(capacity) => self.fill_erased(0, capacity),
+            None => Err(Error::OutOfBounds),
+        }
+    }
+
+    /// Writes `data` to `addr` in a single transaction.
+    ///
+    /// MRAM has no page size limit and completes the write immediately, so
+    /// there is no status polling involved.
+    fn write_bytes(&mut self, addr: u32, data: &mut [u8]) -> Result<(), Error<SPI, CS>> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        self.write_enable()?;
+
+        let (spi, cs) = (&mut self.spi, &mut self.cs);
+        framed_command(spi, cs, None, Opcode::Write as u8, Some(addr), 0, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn test_write_single_transaction() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().busy_polls = 0;
+        let mut mram = Mram::init(spi, cs).unwrap();
+
+        let mut data = [0x55; 600];
+        mram.write_bytes(0x100, &mut data).unwrap();
+        assert_eq!(chip.borrow().opcodes(), [0x05, 0x06, 0x02]);
+        assert_eq!(chip.borrow().transactions[2].len(), 4 + 600);
+    }
+
+    #[test]
+    fn test_erase() {
+        let (chip, spi, cs) = mock::chip(0x4000);
+        chip.borrow_mut().mram = true;
+        chip.borrow_mut().memory.iter_mut().for_each(|b| *b = 0);
+        let mut mram = Mram::init(spi, cs).unwrap();
+
+        mram.erase_sectors(0x1234, 1).unwrap();
+        assert!(chip.borrow().memory[0x1000..0x2000]
+            .iter()
+            .all(|&b| b == 0xFF));
+        assert_eq!(chip.borrow().memory[0xFFF], 0);
+        assert_eq!(chip.borrow().memory[0x2000], 0);
+
+        match mram.erase_all() {
+            Err(Error::OutOfBounds) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        mram.set_capacity(0x4000);
+        match mram.erase_sectors(0x3000, 2) {
+            Err(Error::OutOfBounds) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        mram.erase_all().unwrap();
+        assert!(chip.borrow().memory.iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn test_protection() {
+        let (chip, spi, cs) = mock::chip(0x4000);
+        chip.borrow_mut().mram = true;
+        let mut wp = mock::Wp(chip.clone());
+        let mut mram = Mram::init(spi, cs).unwrap();
+
+        let protection = Protection { bp: 2, srwd: true };
+        mram.set_protection(protection).unwrap();
+        assert_eq!(chip.borrow().status, 0b1000_1000);
+        assert_eq!(mram.get_protection().unwrap(), protection);
+
+        wp.set_low().unwrap();
+        match mram.set_protection(Protection::NONE) {
+            Err(Error::StatusLocked) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(chip.borrow().status, 0b1000_1000);
+        wp.set_high().unwrap();
+        mram.set_protection(Protection::NONE).unwrap();
+        assert_eq!(chip.borrow().status, 0);
+    }
+}
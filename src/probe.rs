@@ -0,0 +1,170 @@
+//! Drivers for whatever kind of chip is connected.
+//!
+//! [`AnyFlash::probe`] identifies the chip and creates the matching driver,
+//! so tools that work on any memory can handle it with one code path.
+
+use crate::mram::Mram;
+use crate::series25::Flash;
+use crate::sfdp::SfdpParams;
+use crate::{BlockDevice, Error, Read};
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+
+/// A driver for either kind of chip supported by this crate.
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)] // there's no heap to box the Flash driver in
+pub enum AnyFlash<'a, SPI: Transfer<u8>, CS: OutputPin> {
+    /// A 25-series Flash chip.
+    Flash(Flash<'a, SPI, CS>),
+    /// A 25-series MRAM chip.
+    Mram(Mram<SPI, CS>),
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> AnyFlash<'_, SPI, CS> {
+    /// Identifies the connected chip and creates the matching driver.
+    ///
+    /// Flash chips answer the JEDEC ID command, while MRAM chips like the
+    /// MR25H series don't implement it and leave the bus at all zeros or all
+    /// ones. So does a missing chip, so a chip without a JEDEC ID is only
+    /// taken for MRAM if it has no SFDP table either, and the byte at address
+    /// 0 can be overwritten with different values without an erase. The
+    /// byte is restored afterwards. If that test fails, this returns
+    /// [`Error::UnknownChip`].
+    ///
+    /// # Parameters
+    ///
+    /// * **`spi`**: An SPI master. Must be configured to operate in the correct
+    ///   mode for the device.
+    /// * **`cs`**: The **C**hip-**S**elect Pin connected to the `\CS` pin of
+    ///   the chip. Will be driven low when accessing the device.
+    pub fn probe(spi: SPI, cs: CS) -> Result<Self, Error<SPI, CS>> {
+        let mut flash = Flash::init(spi, cs)?;
+        let id = flash.read_jedec_id()?;
+        info!("AnyFlash::probe: {:?}", id);
+
+        match id.mfr_code() {
+            0x00 | 0xFF => {
+                if SfdpParams::read(&mut flash)?.is_some() {
+                    return Ok(AnyFlash::Flash(flash));
+                }
+                let (spi, cs) = flash.release().map_err(|(_, e)| e)?;
+                let mut mram = Mram::init(spi, cs)?;
+                if overwrites(&mut mram)? {
+                    Ok(AnyFlash::Mram(mram))
+                } else {
+                    Err(Error::UnknownChip)
+                }
+            }
+            _ => Ok(AnyFlash::Flash(flash)),
+        }
+    }
+
+    /// Releases the SPI master and the chip-select pin, consuming the driver.
+    ///
+    /// See [`Flash::release`] for when this fails.
+    #[allow(clippy::result_large_err)] // there's no heap to box the driver in
+    pub fn release(self) -> Result<(SPI, CS), (Self, Error<SPI, CS>)> {
+        match self {
+            AnyFlash::Flash(flash) => flash
+                .release()
+                .map_err(|(flash, e)| (AnyFlash::Flash(flash), e)),
+            AnyFlash::Mram(mram) => Ok(mram.release()),
+        }
+    }
+}
+
+/// Checks whether the byte at address 0 can be overwritten freely, which
+/// MRAM can do, but Flash can't without an erase in between.
+fn overwrites<SPI, CS>(mram: &mut Mram<SPI, CS>) -> Result<bool, Error<SPI, CS>>
+where
+    SPI: Transfer<u8>,
+    CS: OutputPin,
+{
+    let mut original = [0];
+    mram.read(0, &mut original)?;
+    for &pattern in [0x55, 0xAA].iter() {
+        mram.write_bytes(0, &mut [pattern])?;
+        let mut byte = [0];
+        mram.read(0, &mut byte)?;
+        if byte[0] != pattern {
+            return Ok(false);
+        }
+    }
+    mram.write_bytes(0, &mut original)?;
+    Ok(true)
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> Read<u32, SPI, CS> for AnyFlash<'_, SPI, CS> {
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<SPI, CS>> {
+        match self {
+            AnyFlash::Flash(flash) => flash.read(addr, buf),
+            AnyFlash::Mram(mram) => mram.read(addr, buf),
+        }
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> BlockDevice<u32, SPI, CS> for AnyFlash<'_, SPI, CS> {
+    fn erase_sectors(&mut self, addr: u32, amount: usize) -> Result<(), Error<SPI, CS>> {
+        match self {
+            AnyFlash::Flash(flash) => flash.erase_sectors(addr, amount),
+            AnyFlash::Mram(mram) => mram.erase_sectors(addr, amount),
+        }
+    }
+
+    fn erase_all(&mut self) -> Result<(), Error<SPI, CS>> {
+        match self {
+            AnyFlash::Flash(flash) => flash.erase_all(),
+            AnyFlash::Mram(mram) => mram.erase_all(),
+        }
+    }
+
+    fn write_bytes(&mut self, addr: u32, data: &mut [u8]) -> Result<(), Error<SPI, CS>> {
+        match self {
+            AnyFlash::Flash(flash) => flash.write_bytes(addr, data),
+            AnyFlash::Mram(mram) => mram.write_bytes(addr, data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn test_probe() {
+        let (_chip, spi, cs) = mock::chip(0x10000);
+        match AnyFlash::probe(spi, cs).unwrap() {
+            AnyFlash::Flash(_) => {}
+            other => panic!("unexpected driver {:?}", other),
+        }
+
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().jedec_id = [0; 3];
+        chip.borrow_mut().mram = true;
+        chip.borrow_mut().memory[0] = 0x12;
+        let mut any = AnyFlash::probe(spi, cs).unwrap();
+        match any {
+            AnyFlash::Mram(_) => {}
+            ref other => panic!("unexpected driver {:?}", other),
+        }
+        assert_eq!(chip.borrow().memory[0], 0x12);
+        chip.borrow_mut().transactions.clear();
+        any.write_bytes(0x100, &mut [0x55; 16]).unwrap();
+        // No status polling after the write.
+        assert_eq!(chip.borrow().opcodes(), [0x06, 0x02]);
+    }
+
+    #[test]
+    fn test_probe_no_chip() {
+        // MISO stuck low: all zeros, and nothing can be written.
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().jedec_id = [0; 3];
+        chip.borrow_mut().protected = true;
+        chip.borrow_mut().memory.iter_mut().for_each(|b| *b = 0);
+        match AnyFlash::probe(spi, cs) {
+            Err(Error::UnknownChip) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! Incremental checking of CRC-protected areas, for catching data decay.
+//!
+//! NOR Flash slowly loses its charge over the years. A [`Scrubber`] reads a
+//! bit of a list of CRC-protected areas on every [`poll`], so it can run from
+//! an idle task, and reports any area whose contents no longer match its CRC.
+//! What to do about a corrupt area (eg. rewriting it from a backup copy) is
+//! up to the application.
+//!
+//! [`poll`]: Scrubber::poll
+
+use crate::delay::Delay;
+use crate::digest::{Crc32, Digest};
+use crate::series25::Flash;
+use crate::utils::Context;
+use crate::Error;
+use core::ops::Range;
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+
+/// An area of the chip and the CRC-32 its contents should have.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Area {
+    /// The addresses covered by the area.
+    pub range: Range<u32>,
+    /// The expected CRC-32 (see [`Crc32`]) of the area's contents.
+    pub crc: u32,
+}
+
+/// The result of a [`Scrubber::poll`] call.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The current area hasn't been read completely yet.
+    Pending,
+    /// The area with this index has been read and matches its CRC.
+    Intact(usize),
+    /// The area with this index has been read and does not match its CRC.
+    Corrupt(usize),
+}
+
+/// Walks a list of [`Area`]s in small steps, over and over again.
+#[derive(Debug)]
+pub struct Scrubber<'a> {
+    areas: &'a [Area],
+    step: u32,
+    index: usize,
+    offset: u32,
+    crc: Crc32,
+}
+
+impl<'a> Scrubber<'a> {
+    /// Creates a scrubber checking `areas`, reading at most `step` bytes per
+    /// call to [`Scrubber::poll`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `step` is 0.
+    pub fn new(areas: &'a [Area], step: u32) -> Self {
+        assert!(step > 0, "scrub step must not be 0");
+        Self {
+            areas,
+            step,
+            index: 0,
+            offset: 0,
+            crc: Crc32::new(),
+        }
+    }
+
+    /// Reads the next step of the current area.
+    ///
+    /// Once an area is complete, its result is returned and the next call
+    /// continues with the next area, starting over after the last one. With
+    /// no areas, this always returns [`Outcome::Pending`].
+    pub fn poll<SPI, CS, D>(
+        &mut self,
+        flash: &mut Flash<'_, SPI, CS, D>,
+    ) -> Result<Outcome, Error<SPI, CS>>
+    where
+        SPI: Transfer<u8>,
+        CS: OutputPin,
+        D: Delay,
+    {
+        let area = match self.areas.get(self.index) {
+            Some(area) => area,
+            None => return Ok(Outcome::Pending),
+        };
+
+        let start = area.range.start + self.offset;
+        let len = self.step.min(area.range.end.saturating_sub(start));
+        flash
+            .digest(start, len, &mut self.crc)
+            .context("scrub", start)?;
+        self.offset += len;
+        if area.range.start + self.offset < area.range.end {
+            return Ok(Outcome::Pending);
+        }
+
+        let index = self.index;
+        let intact = self.crc.finish() == area.crc;
+        self.index = (index + 1) % self.areas.len();
+        self.offset = 0;
+        self.crc = Crc32::new();
+        if intact {
+            Ok(Outcome::Intact(index))
+        } else {
+            warn!("scrub: area {} at {:?} is corrupt", index, area.range);
+            Ok(Outcome::Corrupt(index))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn test_scrub() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().memory[0x100..0x109].copy_from_slice(b"123456789");
+        let mut flash = Flash::init(spi, cs).unwrap();
+
+        let areas = [
+            Area {
+                range: 0x100..0x109,
+                crc: 0xCBF4_3926,
+            },
+            Area {
+                range: 0x200..0x201,
+                crc: 0,
+            },
+        ];
+        let mut scrubber = Scrubber::new(&areas, 4);
+        let mut outcomes = Vec::new();
+        for _ in 0..5 {
+            outcomes.push(scrubber.poll(&mut flash).unwrap());
+        }
+        assert_eq!(
+            outcomes,
+            [
+                Outcome::Pending,
+                Outcome::Pending,
+                Outcome::Intact(0),
+                Outcome::Corrupt(1),
+                Outcome::Pending,
+            ]
+        );
+    }
+}
@@ -1,11 +1,19 @@
 //! Driver for 25-series SPI Flash and EEPROM chips.
 
-use crate::{utils::HexSlice, BlockDevice, Error, Read};
+use crate::delay::{Delay, NoDelay};
+use crate::digest::Digest;
+use crate::geometry::{align_down, align_up, Geometry, BLOCK32_SIZE, BLOCK64_SIZE, SECTOR_SIZE};
+#[cfg(feature = "metrics")]
+use crate::metrics::{Clock, Stats, Timings};
+use crate::sfdp::{AddressBytes, SfdpParams};
+use crate::utils::{framed_command, spi_command, CsDelay, HexSlice};
+use crate::{BlockDevice, Error, Read};
 use bitflags::bitflags;
 use core::convert::TryInto;
 use core::fmt;
+use core::ops::Range;
 use embedded_hal::blocking::spi::Transfer;
-use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::digital::v2::{OutputPin, StatefulOutputPin};
 
 /// 3-Byte JEDEC manufacturer and device identification.
 pub struct Identification {
@@ -27,13 +35,10 @@ impl Identification {
         // 0xC2 is the company identifier for Cypress (Ramtron)
 
         // Find the end of the continuation bytes (0x7F)
-        let mut start_idx = 0;
-        for i in 0..(buf.len() - 2) {
-            if buf[i] != 0x7F {
-                start_idx = i;
-                break;
-            }
-        }
+        let start_idx = buf[..buf.len() - 2]
+            .iter()
+            .position(|&byte| byte != 0x7F)
+            .unwrap_or(0);
 
         Self {
             bytes: [buf[start_idx], buf[start_idx + 1], buf[start_idx + 2]],
@@ -68,31 +73,56 @@ impl fmt::Debug for Identification {
     }
 }
 
+#[cfg(feature = "defmt")]
+impl defmt::Format for Identification {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "Identification({=[u8]:02x})", self.bytes)
+    }
+}
+
 #[allow(unused)] // TODO support more features
 enum Opcode {
-    /// Read the 8-bit legacy device ID.
+    /// Release from deep power-down, and read the 8-bit legacy device ID.
     ReadDeviceId = 0xAB,
+    /// Enter deep power-down.
+    PowerDown = 0xB9,
     /// Read the 8-bit manufacturer and device IDs.
     ReadMfDId = 0x90,
+    /// Read the 64-bit factory-programmed unique ID.
+    ReadUniqueId = 0x4B,
     /// Read 16-bit manufacturer ID and 8-bit device ID.
     ReadJedecId = 0x9F,
+    /// Read the Serial Flash Discoverable Parameters.
+    ReadSfdp = 0x5A,
     /// Set the write enable latch.
     WriteEnable = 0x06,
     /// Clear the write enable latch.
     WriteDisable = 0x04,
     /// Read the 8-bit status register.
     ReadStatus = 0x05,
+    /// Read the second 8-bit status register (Winbond and compatibles).
+    ReadStatus2 = 0x35,
+    /// Read the third 8-bit status register (Winbond and compatibles).
+    ReadStatus3 = 0x15,
     /// Write the 8-bit status register. Not all bits are writeable.
     WriteStatus = 0x01,
+    /// Write the second 8-bit status register (Winbond and compatibles).
+    WriteStatus2 = 0x31,
+    /// Write the third 8-bit status register (Winbond and compatibles).
+    WriteStatus3 = 0x11,
     Read = 0x03,
+    /// Read with dummy bytes after the address, for higher clock rates.
+    FastRead = 0x0B,
     PageProg = 0x02, // directly writes to EEPROMs too
     SectorErase = 0x20,
-    BlockErase = 0xD8,
+    BlockErase32 = 0x52,
+    BlockErase64 = 0xD8,
     ChipErase = 0xC7,
 }
 
 bitflags! {
     /// Status register bits.
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
     pub struct Status: u8 {
         /// Erase or write in progress.
         const BUSY = 1 << 0;
@@ -105,20 +135,432 @@ bitflags! {
     }
 }
 
+impl Status {
+    /// Returns the range of addresses write-protected by the `PROT` bits on a
+    /// chip of `capacity` bytes.
+    ///
+    /// This decodes the scheme used by most 25-series chips with 3 protection
+    /// bits and no top/bottom selection: the protected region always ends at
+    /// the top of the chip, and doubles in size with every increment of the
+    /// protection bits, starting at 1/64th of the chip or 64 KiB, whichever is
+    /// larger. All bits set protects the whole chip. Chips with other schemes
+    /// (eg. bottom protection or 4 protection bits) are not decoded correctly.
+    pub fn protected_range(self, capacity: u32) -> Range<u32> {
+        let bp = (self & Status::PROT).bits() >> 2;
+        match bp {
+            0 => capacity..capacity,
+            0b111 => 0..capacity,
+            _ => {
+                let unit = (capacity / 64).max(64 * 1024);
+                let size = (unit << (bp - 1)).min(capacity);
+                capacity - size..capacity
+            }
+        }
+    }
+
+    /// Returns a [`Display`](fmt::Display)able description of the status that
+    /// also includes the range protected on a chip of `capacity` bytes.
+    pub fn describe(self, capacity: u32) -> StatusDescription {
+        StatusDescription {
+            status: self,
+            capacity,
+        }
+    }
+}
+
+/// Lists the set flags and the protection bits, eg. `WEL BP=2`.
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for &(flag, name) in [(Status::BUSY, "BUSY"), (Status::WEL, "WEL")].iter() {
+            if self.contains(flag) {
+                write!(f, "{} ", name)?;
+            }
+        }
+        write!(f, "BP={}", (*self & Status::PROT).bits() >> 2)?;
+        if self.contains(Status::SRWD) {
+            f.write_str(" SRWD")?;
+        }
+        Ok(())
+    }
+}
+
+/// Block protection settings in the status register.
+///
+/// See [`Status::protected_range`] for how the protection bits are decoded.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Protection {
+    /// Value of the 3 block protection bits BP0-BP2, 0 to 7.
+    pub bp: u8,
+    /// Whether the status register is locked while `/WP` is low.
+    pub srwd: bool,
+}
+
+impl Protection {
+    /// No protection.
+    pub const NONE: Self = Self { bp: 0, srwd: false };
+
+    /// Returns the settings protecting the smallest region at the top of a
+    /// chip of `capacity` bytes that includes the top `size` bytes.
+    pub fn top(capacity: u32, size: u32) -> Self {
+        let bp = (0..=7)
+            .find(|&bp| {
+                let range = Self { bp, srwd: false }.protected_range(capacity);
+                range.end - range.start >= size
+            })
+            .unwrap_or(7);
+        Self { bp, srwd: false }
+    }
+
+    /// Returns the range of addresses protected on a chip of `capacity`
+    /// bytes.
+    pub fn protected_range(self, capacity: u32) -> Range<u32> {
+        self.status().protected_range(capacity)
+    }
+
+    pub(crate) fn status(self) -> Status {
+        let mut status = Status::from_bits_truncate(self.bp << 2) & Status::PROT;
+        status.set(Status::SRWD, self.srwd);
+        status
+    }
+}
+
+impl From<Status> for Protection {
+    fn from(status: Status) -> Self {
+        Self {
+            bp: (status & Status::PROT).bits() >> 2,
+            srwd: status.contains(Status::SRWD),
+        }
+    }
+}
+
+/// A [`Status`] with the protected range decoded, see [`Status::describe`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StatusDescription {
+    status: Status,
+    capacity: u32,
+}
+
+/// Formats as eg. `BP=2 (protected: 0x1e0000..0x200000)`.
+impl fmt::Display for StatusDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let protected = self.status.protected_range(self.capacity);
+        if protected.start == protected.end {
+            write!(f, "{} (nothing protected)", self.status)
+        } else {
+            write!(
+                f,
+                "{} (protected: {:#x}..{:#x})",
+                self.status, protected.start, protected.end
+            )
+        }
+    }
+}
+
+bitflags! {
+    /// Bits of the second status register of Winbond and compatible chips.
+    ///
+    /// Other chips may not have this register, or use its bits differently.
+    pub struct Status2: u8 {
+        /// **S**tatus **R**egister **L**ock, makes the status registers
+        /// read-only until the next power cycle.
+        const SRL = 1 << 0;
+        /// **Q**uad **E**nable, switches /WP and /HOLD to data lines.
+        const QE = 1 << 1;
+        /// The 3 one-time programmable lock bits of the security registers.
+        const LB = 0b0011_1000;
+        /// **C**o**mp**lement protect, inverts the protected range.
+        const CMP = 1 << 6;
+        /// An erase or program is **sus**pended.
+        const SUS = 1 << 7;
+    }
+}
+
+/// Lists the set flags, eg. `QE LB=1`, or `-` if there are none.
+impl fmt::Display for Status2 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = 0;
+        let mut part = |f: &mut fmt::Formatter<'_>, args: fmt::Arguments<'_>| {
+            parts += 1;
+            if parts > 1 {
+                f.write_str(" ")?;
+            }
+            f.write_fmt(args)
+        };
+        for &(flag, name) in [(Status2::SRL, "SRL"), (Status2::QE, "QE")].iter() {
+            if self.contains(flag) {
+                part(f, format_args!("{}", name))?;
+            }
+        }
+        if self.intersects(Status2::LB) {
+            part(
+                f,
+                format_args!("LB={:#05b}", (*self & Status2::LB).bits() >> 3),
+            )?;
+        }
+        for &(flag, name) in [(Status2::CMP, "CMP"), (Status2::SUS, "SUS")].iter() {
+            if self.contains(flag) {
+                part(f, format_args!("{}", name))?;
+            }
+        }
+        if parts == 0 {
+            f.write_str("-")?;
+        }
+        Ok(())
+    }
+}
+
+bitflags! {
+    /// Bits of the third status register of Winbond and compatible chips.
+    ///
+    /// Other chips may not have this register, or use its bits differently.
+    pub struct Status3: u8 {
+        /// **W**rite **P**rotect **S**election, use the individual block locks
+        /// instead of the protection bits.
+        const WPS = 1 << 2;
+        /// Output **dr**i**v**er strength, 0 is the strongest.
+        const DRV = 0b0110_0000;
+    }
+}
+
+/// Lists the set flags and the driver strength, eg. `WPS DRV=3`.
+impl fmt::Display for Status3 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.contains(Status3::WPS) {
+            f.write_str("WPS ")?;
+        }
+        write!(f, "DRV={}", (*self & Status3::DRV).bits() >> 5)
+    }
+}
+
+bitflags! {
+    /// Optional features of a chip, as returned by [`Flash::capabilities`].
+    pub struct Capabilities: u8 {
+        /// The chip has more than 16 MiB and needs 4-byte addresses to reach
+        /// all of it.
+        const FOUR_BYTE_ADDRESS = 1 << 0;
+        /// Dual Output or Dual I/O Fast Read.
+        const DUAL = 1 << 1;
+        /// Quad Output or Quad I/O Fast Read.
+        const QUAD = 1 << 2;
+        /// The chip has an SFDP table.
+        const SFDP = 1 << 3;
+        /// Erase and program operations can be suspended.
+        const SUSPEND = 1 << 4;
+        /// One-time programmable security registers.
+        const SECURITY_REGISTERS = 1 << 5;
+        /// Individual sectors and blocks can be locked.
+        const INDIVIDUAL_LOCK = 1 << 6;
+    }
+}
+
+impl Capabilities {
+    /// Looks up the features of a known chip family by its JEDEC ID.
+    ///
+    /// The entries describe a family as a whole, individual parts may lack
+    /// some of the features.
+    fn from_jedec_id(id: &Identification) -> Option<Self> {
+        let (memory_type, code) = match id.device_id() {
+            [memory_type, code] => (*memory_type, *code),
+            _ => return None,
+        };
+        let common = Self::DUAL | Self::QUAD | Self::SUSPEND;
+        let family = match (id.mfr_code(), memory_type) {
+            // Winbond W25Q
+            (0xEF, 0x40) | (0xEF, 0x60) | (0xEF, 0x70) => {
+                common | Self::SECURITY_REGISTERS | Self::INDIVIDUAL_LOCK
+            }
+            // Macronix MX25L, GigaDevice GD25Q
+            (0xC2, 0x20) | (0xC8, 0x40) => common | Self::SECURITY_REGISTERS,
+            // ISSI IS25LP, Micron N25Q
+            (0x9D, 0x60) | (0x20, 0xBA) => common,
+            // Micron (ST) M25P
+            (0x20, 0x20) => Self::empty(),
+            _ => return None,
+        };
+        if code > 0x18 {
+            Some(family | Self::FOUR_BYTE_ADDRESS)
+        } else {
+            Some(family)
+        }
+    }
+}
+
+/// [`geometry::PAGE_SIZE`](crate::geometry::PAGE_SIZE), for sizing buffers.
+const PAGE_SIZE: usize = crate::geometry::PAGE_SIZE as usize;
+
+/// Time the chip needs after Release Power-Down (tRES1/tRES2), in µs.
+const RELEASE_POWER_DOWN_US: u32 = 30;
+
+/// Interval between status reads of [`Flash::erase_all_with_progress`], in µs.
+const PROGRESS_INTERVAL_US: u32 = 10_000;
+
+/// How reads and writes that run past the end of the chip are handled.
+///
+/// This only takes effect once the driver knows the chip's capacity, see
+/// [`Flash::set_capacity`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Overflow {
+    /// Fail with [`Error::OutOfBounds`] without accessing the chip.
+    ///
+    /// Erases always behave like this, regardless of the overflow mode.
+    Error,
+    /// Split the access at the end of the chip and continue at address 0.
+    ///
+    /// This is what address mirroring does on most chips anyway, but doing it
+    /// in the driver also works for page programs, which would otherwise wrap
+    /// around inside the last page. Accesses larger than the whole chip still
+    /// fail with [`Error::OutOfBounds`].
+    Wrap,
+}
+
+/// How the write enable latch is set before erase and program commands.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WriteEnable {
+    /// Send Write Enable before every erase or program command.
+    ///
+    /// This is what 25-series chips expect, since they clear the latch when a
+    /// command completes.
+    Always,
+    /// Send Write Enable only before the first command of an operation, and
+    /// Write Disable after the last one.
+    ///
+    /// This is for chips that keep the latch set until it is cleared
+    /// explicitly. If the operation fails midway, the latch may be left set.
+    Once,
+}
+
+/// How the driver waits for erase and program operations to finish, see
+/// [`Flash::set_polling`].
+///
+/// The default reads the status register back to back until the chip is
+/// done, without any timeout.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Polling {
+    /// Time to wait after the first busy status read, in µs.
+    pub interval_us: u32,
+    /// The interval doubles after every busy status read, up to this many µs.
+    /// Set it to `interval_us` for a fixed interval.
+    pub max_interval_us: u32,
+    /// Fail with [`Error::Timeout`] once the intervals waited so far add up
+    /// to this many µs.
+    pub timeout_us: Option<u32>,
+}
+
+/// A step of [`Flash::secure_erase`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WipePhase {
+    /// The first erase.
+    Erase,
+    /// Programming all bytes to zero.
+    Zero,
+    /// The final erase.
+    FinalErase,
+}
+
+/// The error returned by [`Flash::secure_erase`].
+pub struct SecureEraseError<SPI: Transfer<u8>, CS: OutputPin> {
+    /// The step that failed.
+    pub phase: WipePhase,
+    /// Why it failed. Verification failures are reported as
+    /// [`Error::Mismatch`].
+    pub error: Error<SPI, CS>,
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> fmt::Debug for SecureEraseError<SPI, CS>
+where
+    SPI::Error: fmt::Debug,
+    CS::Error: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecureEraseError")
+            .field("phase", &self.phase)
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+/// How long an erase took, as returned by [`Flash::last_erase_duration`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EraseDuration {
+    /// The number of status register reads until the chip was done.
+    ///
+    /// This depends on the SPI clock and the time spent between reads, but
+    /// is available without a clock.
+    pub polls: u32,
+    /// The elapsed time in microseconds, if the `metrics` feature is enabled
+    /// and a clock is set with `Flash::set_clock`.
+    pub micros: Option<u64>,
+}
+
+/// What [`Flash::self_test`] measured while testing a sector.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SelfTestReport {
+    /// How long the first erase of the sector took.
+    pub erase: EraseDuration,
+    /// The number of status register reads while programming the sector,
+    /// summed over all pages.
+    pub program_polls: u32,
+    /// The time programming the sector took in microseconds, if the
+    /// `metrics` feature is enabled and a clock is set with
+    /// `Flash::set_clock`.
+    pub program_micros: Option<u64>,
+    /// The time reading back and comparing the programmed pattern took in
+    /// microseconds, under the same conditions as `program_micros`.
+    pub verify_micros: Option<u64>,
+}
+
+/// An operation started without waiting for it to finish.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Pending {
+    SectorErase(u32),
+    ChipErase,
+    PageProgram,
+}
+
 /// Driver for 25-series SPI Flash chips.
 ///
 /// # Type Parameters
 ///
+/// * **`'a`**: How long the erase map set with [`Flash::set_erase_map`] and
+///   the clock set with `Flash::set_clock` are borrowed.
 /// * **`SPI`**: The SPI master to which the flash chip is attached.
 /// * **`CS`**: The **C**hip-**S**elect line attached to the `\CS`/`\CE` pin of
 ///   the flash chip.
+/// * **`D`**: The delay provider set with [`Flash::with_delay`], or
+///   [`NoDelay`] without one.
 #[derive(Debug)]
-pub struct Flash<SPI: Transfer<u8>, CS: OutputPin> {
+pub struct Flash<'a, SPI: Transfer<u8>, CS: OutputPin, D = NoDelay> {
     spi: SPI,
     cs: CS,
+    capacity: Option<u32>,
+    page_size: u32,
+    sector_size: u32,
+    block_size: u32,
+    overflow: Overflow,
+    write_enable: WriteEnable,
+    verify_write_enable: bool,
+    verify_completion: bool,
+    verify_writes: bool,
+    check_protection: bool,
+    suspend_aware: bool,
+    erase_hook: Option<fn(u32)>,
+    erase_map: Option<&'a mut [u8]>,
+    polls: u32,
+    last_erase: Option<EraseDuration>,
+    delay: Option<D>,
+    polling: Polling,
+    cs_setup_us: u32,
+    cs_hold_us: u32,
+    fast_read_dummy: u8,
+    paranoid_read: Option<u8>,
+    pending: Option<Pending>,
+    #[cfg(feature = "metrics")]
+    clock: Option<&'a dyn Clock>,
+    #[cfg(feature = "metrics")]
+    timings: Timings,
 }
 
-impl<SPI: Transfer<u8>, CS: OutputPin> Flash<SPI, CS> {
+impl<'a, SPI: Transfer<u8>, CS: OutputPin> Flash<'a, SPI, CS> {
     /// Creates a new 25-series flash driver.
     ///
     /// # Parameters
@@ -128,7 +570,36 @@ impl<SPI: Transfer<u8>, CS: OutputPin> Flash<SPI, CS> {
     /// * **`cs`**: The **C**hip-**S**elect Pin connected to the `\CS`/`\CE` pin
     ///   of the flash chip. Will be driven low when accessing the device.
     pub fn init(spi: SPI, cs: CS) -> Result<Self, Error<SPI, CS>> {
-        let mut this = Self { spi, cs };
+        let mut this = Self {
+            spi,
+            cs,
+            capacity: None,
+            page_size: crate::geometry::PAGE_SIZE,
+            sector_size: SECTOR_SIZE,
+            block_size: BLOCK64_SIZE,
+            overflow: Overflow::Error,
+            write_enable: WriteEnable::Always,
+            verify_write_enable: false,
+            verify_completion: false,
+            verify_writes: false,
+            check_protection: false,
+            suspend_aware: false,
+            erase_hook: None,
+            erase_map: None,
+            polls: 0,
+            last_erase: None,
+            delay: None,
+            polling: Polling::default(),
+            cs_setup_us: 0,
+            cs_hold_us: 0,
+            fast_read_dummy: 1,
+            paranoid_read: None,
+            pending: None,
+            #[cfg(feature = "metrics")]
+            clock: None,
+            #[cfg(feature = "metrics")]
+            timings: Timings::default(),
+        };
         let status = this.read_status()?;
         info!("Flash::init: status = {:?}", status);
 
@@ -140,145 +611,2697 @@ impl<SPI: Transfer<u8>, CS: OutputPin> Flash<SPI, CS> {
 
         Ok(this)
     }
+}
 
-    fn command(&mut self, bytes: &mut [u8]) -> Result<(), Error<SPI, CS>> {
-        // If the SPI transfer fails, make sure to disable CS anyways
-        self.cs.set_low().map_err(Error::Gpio)?;
-        let spi_result = self.spi.transfer(bytes).map_err(Error::Spi);
-        self.cs.set_high().map_err(Error::Gpio)?;
-        spi_result?;
-        Ok(())
+impl<'a, SPI: Transfer<u8>, CS: OutputPin, D: Delay> Flash<'a, SPI, CS, D> {
+    /// Releases the SPI master and the chip-select pin, consuming the driver.
+    ///
+    /// Fails with [`Error::Busy`] if an operation started with one of the
+    /// `start_*` methods hasn't been completed, or if the chip reports that
+    /// it is still busy, eg. because a previous call failed midway. The
+    /// driver is handed back along with the error, so the operation can be
+    /// completed first.
+    #[allow(clippy::result_large_err)] // there's no heap to box the driver in
+    pub fn release(mut self) -> Result<(SPI, CS), (Self, Error<SPI, CS>)> {
+        if let Err(e) = self.ensure_idle() {
+            return Err((self, e));
+        }
+        match self.is_busy() {
+            Ok(false) => Ok((self.spi, self.cs)),
+            Ok(true) => Err((self, Error::Busy)),
+            Err(e) => Err((self, e)),
+        }
     }
 
-    /// Reads the JEDEC manufacturer/device identification.
-    pub fn read_jedec_id(&mut self) -> Result<Identification, Error<SPI, CS>> {
-        // Optimistically read 12 bytes, even though some identifiers will be shorter
-        let mut buf: [u8; 12] = [0; 12];
-        buf[0] = Opcode::ReadJedecId as u8;
-        self.command(&mut buf)?;
+    /// Tells the driver the capacity of the chip in bytes.
+    ///
+    /// Without this, the driver passes every address to the chip as-is. Once
+    /// the capacity is known, reads and writes that run past the end of the
+    /// chip are handled according to the [`Overflow`] mode.
+    pub fn set_capacity(&mut self, capacity: u32) {
+        self.capacity = Some(capacity);
+    }
 
-        // Skip buf[0] (SPI read response byte)
-        Ok(Identification::from_jedec_id(&buf[1..]))
+    /// Returns the size of the sectors erased by
+    /// [`BlockDevice::erase_sectors`].
+    ///
+    /// This is 4 KiB unless a different geometry was set with
+    /// [`Flash::set_geometry`] or [`Flash::detect_geometry`].
+    pub fn sector_size(&self) -> u32 {
+        self.sector_size
     }
 
-    /// Reads the status register.
-    pub fn read_status(&mut self) -> Result<Status, Error<SPI, CS>> {
-        let mut buf = [Opcode::ReadStatus as u8, 0];
-        self.command(&mut buf)?;
+    /// Returns the size of the pages written by a single Page Program.
+    pub fn page_size(&self) -> u32 {
+        self.page_size
+    }
 
-        Ok(Status::from_bits_truncate(buf[1]))
+    /// Returns the geometry of the chip, or `None` if its capacity is not
+    /// known.
+    pub fn geometry(&self) -> Option<Geometry> {
+        self.capacity.map(|capacity| Geometry {
+            page_size: self.page_size,
+            sector_size: self.sector_size,
+            block_size: self.block_size,
+            capacity,
+        })
     }
 
-    fn write_enable(&mut self) -> Result<(), Error<SPI, CS>> {
-        let mut cmd_buf = [Opcode::WriteEnable as u8];
-        self.command(&mut cmd_buf)?;
-        Ok(())
+    /// Tells the driver the geometry of the chip.
+    ///
+    /// Writes are split at page boundaries, and
+    /// [`BlockDevice::erase_sectors`] erases sectors of the given size. This
+    /// also sets the capacity, like [`Flash::set_capacity`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the page size is not a power of two, or the sector size is
+    /// not 4, 32 or 64 KiB, the sizes the erase commands work with.
+    pub fn set_geometry(&mut self, geometry: Geometry) {
+        assert!(
+            geometry.page_size.is_power_of_two(),
+            "page size must be a power of two"
+        );
+        assert!(
+            [SECTOR_SIZE, BLOCK32_SIZE, BLOCK64_SIZE].contains(&geometry.sector_size),
+            "unsupported sector size"
+        );
+        self.page_size = geometry.page_size;
+        self.sector_size = geometry.sector_size;
+        self.block_size = geometry.block_size;
+        self.capacity = Some(geometry.capacity);
     }
 
-    fn wait_done(&mut self) -> Result<(), Error<SPI, CS>> {
-        // TODO: Consider changing this to a delay based pattern
-        while self.read_status()?.contains(Status::BUSY) {}
-        Ok(())
+    /// Determines the geometry of the chip and configures the driver with it.
+    ///
+    /// The JEDEC ID is looked up in a table of known chips first. Unknown
+    /// chips are asked for their SFDP parameters instead. Returns `None`, and
+    /// changes nothing, if neither works.
+    pub fn detect_geometry(&mut self) -> Result<Option<Geometry>, Error<SPI, CS>> {
+        let id = self.read_jedec_id()?;
+        let geometry = match Geometry::from_jedec_id(&id) {
+            Some(geometry) => Some(geometry),
+            None => SfdpParams::read(self)?
+                .as_ref()
+                .and_then(Geometry::from_sfdp),
+        };
+        info!("Flash::detect_geometry: {:?} -> {:?}", id, geometry);
+        if let Some(geometry) = geometry {
+            self.set_geometry(geometry);
+        }
+        Ok(geometry)
     }
-}
 
-impl<SPI: Transfer<u8>, CS: OutputPin> Read<u32, SPI, CS> for Flash<SPI, CS> {
-    /// Reads flash contents into `buf`, starting at `addr`.
+    /// Returns the opcode erasing one sector of [`Flash::sector_size`].
+    fn sector_erase_opcode(&self) -> Opcode {
+        match self.sector_size {
+            BLOCK64_SIZE => Opcode::BlockErase64,
+            BLOCK32_SIZE => Opcode::BlockErase32,
+            _ => Opcode::SectorErase,
+        }
+    }
+
+    /// Returns the capacity of the chip in bytes, if known.
+    pub fn capacity(&self) -> Option<u32> {
+        self.capacity
+    }
+
+    /// Reads the chip's SFDP table and sets the geometry from it.
     ///
-    /// Note that `addr` is not fully decoded: Flash chips will typically only
-    /// look at the lowest `N` bits needed to encode their size, which means
-    /// that the contents are "mirrored" to addresses that are a multiple of the
-    /// flash size. Only 24 bits of `addr` are transferred to the device in any
-    /// case, limiting the maximum size of 25-series SPI flash chips to 16 MiB.
+    /// Returns the parsed parameters, or `None` if the chip has no usable
+    /// SFDP data, in which case nothing is changed. Chips larger than 16 MiB
+    /// don't get a geometry, since the driver only uses 3-byte addresses.
+    pub fn configure_from_sfdp(&mut self) -> Result<Option<SfdpParams>, Error<SPI, CS>> {
+        let params = SfdpParams::read(self)?;
+        if let Some(params) = &params {
+            info!("Flash::configure_from_sfdp: {:?}", params);
+            if let Some(geometry) = Geometry::from_sfdp(params) {
+                self.set_geometry(geometry);
+            }
+        }
+        Ok(params)
+    }
+
+    /// Sets how reads and writes that run past the end of the chip are
+    /// handled. Defaults to [`Overflow::Error`].
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
+    }
+
+    /// Sets when the write enable latch is set. Defaults to
+    /// [`WriteEnable::Always`].
+    pub fn set_write_enable(&mut self, write_enable: WriteEnable) {
+        self.write_enable = write_enable;
+    }
+
+    /// Sets whether the status register is read back after every Write
+    /// Enable command to check that the latch is actually set.
     ///
-    /// # Parameters
+    /// If it isn't, the operation fails with [`Error::UnexpectedStatus`]
+    /// instead of being silently ignored by the chip. Disabled by default.
+    pub fn set_verify_write_enable(&mut self, verify: bool) {
+        self.verify_write_enable = verify;
+    }
+
+    /// Sets whether the status register is checked after every erase and
+    /// program command to make sure the chip executed it.
     ///
-    /// * `addr`: 24-bit address to start reading at.
-    /// * `buf`: Destination buffer to fill.
-    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<SPI, CS>> {
-        // TODO what happens if `buf` is empty?
+    /// Chips silently ignore erase and program commands that target a
+    /// write-protected region, but leave the write enable latch set in that
+    /// case. With this enabled, such commands fail with
+    /// [`Error::UnexpectedStatus`]. Disabled by default. This check is skipped
+    /// with [`WriteEnable::Once`], where the latch is expected to stay set.
+    pub fn set_verify_completion(&mut self, verify: bool) {
+        self.verify_completion = verify;
+    }
+
+    /// Sets whether written data is read back and compared after every page
+    /// program.
+    ///
+    /// Bit errors while programming are otherwise completely silent. With
+    /// this enabled, [`BlockDevice::write_bytes`] fails with
+    /// [`Error::Mismatch`] at the first byte that reads back differently, eg.
+    /// because it wasn't erased. Writes are then programmed in chunks of at
+    /// most 256 bytes, the size of the buffer holding the data for the
+    /// comparison. Disabled by default.
+    pub fn set_verify_writes(&mut self, verify: bool) {
+        self.verify_writes = verify;
+    }
 
-        let mut cmd_buf = [
-            Opcode::Read as u8,
-            (addr >> 16) as u8,
-            (addr >> 8) as u8,
-            addr as u8,
-        ];
+    /// Sets whether the protection bits in the status register are checked
+    /// before every erase and write.
+    ///
+    /// If the operation would touch a protected region, it fails with
+    /// [`Error::Protected`] instead of being silently ignored by the chip. This
+    /// only works once the capacity is known (see [`Flash::set_capacity`]),
+    /// and only for chips using the protection scheme decoded by
+    /// [`Status::protected_range`]. Disabled by default.
+    pub fn set_check_protection(&mut self, check: bool) {
+        self.check_protection = check;
+    }
 
-        self.cs.set_low().map_err(Error::Gpio)?;
-        let mut spi_result = self.spi.transfer(&mut cmd_buf);
-        if spi_result.is_ok() {
-            spi_result = self.spi.transfer(buf);
+    /// Fails with [`Error::Protected`] if the protection bits cover any part
+    /// of `range`, and checking protection is enabled.
+    /// Fails with [`Error::OutOfBounds`] if `len` bytes at `start` don't fit
+    /// in the chip.
+    ///
+    /// Erases go through this instead of [`Overflow`] handling, so they never
+    /// wrap around, and neither does the chip's address mirroring.
+    fn ensure_in_bounds(&self, start: u32, len: u64) -> Result<(), Error<SPI, CS>> {
+        match self.capacity {
+            Some(capacity) if u64::from(start) + len > u64::from(capacity) => {
+                Err(Error::OutOfBounds)
+            }
+            _ => Ok(()),
         }
-        self.cs.set_high().map_err(Error::Gpio)?;
-        spi_result.map(|_| ()).map_err(Error::Spi)
     }
-}
 
-impl<SPI: Transfer<u8>, CS: OutputPin> BlockDevice<u32, SPI, CS> for Flash<SPI, CS> {
-    fn erase_sectors(&mut self, addr: u32, amount: usize) -> Result<(), Error<SPI, CS>> {
-        for c in 0..amount {
-            self.write_enable()?;
+    fn ensure_unprotected(&mut self, range: Range<u32>) -> Result<(), Error<SPI, CS>> {
+        let capacity = match self.capacity {
+            Some(capacity) if self.check_protection => capacity,
+            _ => return Ok(()),
+        };
 
-            let current_addr: u32 = (addr as usize + c * 256).try_into().unwrap();
-            let mut cmd_buf = [
-                Opcode::SectorErase as u8,
-                (current_addr >> 16) as u8,
-                (current_addr >> 8) as u8,
-                current_addr as u8,
-            ];
-            self.command(&mut cmd_buf)?;
-            self.wait_done()?;
+        let protected = self.read_status()?.protected_range(capacity);
+        if range.start < protected.end && protected.start < range.end {
+            return Err(Error::Protected {
+                addr: range.start.max(protected.start),
+            });
         }
-
         Ok(())
     }
 
-    fn write_bytes(&mut self, addr: u32, data: &mut [u8]) -> Result<(), Error<SPI, CS>> {
-        for (c, chunk) in data.chunks_mut(256).enumerate() {
-            self.write_enable()?;
+    /// Sets whether the driver checks for suspended operations when waiting
+    /// for an erase or program to finish.
+    ///
+    /// A suspended operation clears BUSY without being complete. With this
+    /// enabled, the driver checks [`Flash::is_suspended`] once BUSY is clear,
+    /// and fails with [`Error::UnexpectedStatus`] if something is suspended.
+    /// Only enable this on chips that keep the SUS bit in the second status
+    /// register. Disabled by default.
+    pub fn set_suspend_aware(&mut self, aware: bool) {
+        self.suspend_aware = aware;
+    }
 
-            let current_addr: u32 = (addr as usize + c * 256).try_into().unwrap();
-            let mut cmd_buf = [
-                Opcode::PageProg as u8,
-                (current_addr >> 16) as u8,
-                (current_addr >> 8) as u8,
-                current_addr as u8,
-            ];
-
-            self.cs.set_low().map_err(Error::Gpio)?;
-            let mut spi_result = self.spi.transfer(&mut cmd_buf);
-            if spi_result.is_ok() {
-                spi_result = self.spi.transfer(chunk);
+    /// Hands the driver a delay provider, used to wait for the chip where
+    /// needed, and returns it with the new delay type.
+    ///
+    /// Without one, the driver never waits, and callers have to add delays
+    /// themselves where the documentation of a method says so. A previously
+    /// set delay provider is dropped.
+    pub fn with_delay<D2: Delay>(self, delay: D2) -> Flash<'a, SPI, CS, D2> {
+        Flash {
+            spi: self.spi,
+            cs: self.cs,
+            capacity: self.capacity,
+            page_size: self.page_size,
+            sector_size: self.sector_size,
+            block_size: self.block_size,
+            overflow: self.overflow,
+            write_enable: self.write_enable,
+            verify_write_enable: self.verify_write_enable,
+            verify_completion: self.verify_completion,
+            verify_writes: self.verify_writes,
+            check_protection: self.check_protection,
+            suspend_aware: self.suspend_aware,
+            erase_hook: self.erase_hook,
+            erase_map: self.erase_map,
+            polls: self.polls,
+            last_erase: self.last_erase,
+            delay: Some(delay),
+            polling: self.polling,
+            cs_setup_us: self.cs_setup_us,
+            cs_hold_us: self.cs_hold_us,
+            fast_read_dummy: self.fast_read_dummy,
+            paranoid_read: self.paranoid_read,
+            pending: self.pending,
+            #[cfg(feature = "metrics")]
+            clock: self.clock,
+            #[cfg(feature = "metrics")]
+            timings: self.timings,
+        }
+    }
+
+    /// Sets how the driver waits for erase and program operations to finish.
+    ///
+    /// Waiting between status reads keeps the SPI bus free for other devices
+    /// and saves power during long erases. The intervals and the timeout only
+    /// take effect with a delay provider set via [`Flash::with_delay`], and
+    /// the timeout only counts the time spent in those intervals, not the
+    /// status reads themselves.
+    pub fn set_polling(&mut self, polling: Polling) {
+        self.polling = polling;
+    }
+
+    /// Sets extra delays around selecting the chip for a command.
+    ///
+    /// `setup_us` is waited after pulling `\CS` low, before the first clock
+    /// edge, and `hold_us` after the last clock edge, before releasing `\CS`
+    /// again. This is for chips or level shifters whose setup and hold times
+    /// a fast SPI master violates. It only takes effect with a delay provider
+    /// set via [`Flash::with_delay`]. Both are 0 by default.
+    pub fn set_cs_delays(&mut self, setup_us: u32, hold_us: u32) {
+        self.cs_setup_us = setup_us;
+        self.cs_hold_us = hold_us;
+    }
+
+    /// Sets a function that is called with the start address of every sector
+    /// after it has been erased.
+    ///
+    /// This lets applications keep their own per-sector erase counters.
+    /// [`BlockDevice::erase_all`] reports every sector only if the capacity
+    /// is known (see [`Flash::set_capacity`]), and none otherwise.
+    pub fn set_erase_hook(&mut self, hook: fn(u32)) {
+        self.erase_hook = Some(hook);
+    }
+
+    /// Sets a bitmap used to remember which sectors are known to be erased.
+    ///
+    /// Bit `n % 8` of `map[n / 8]` tracks sector `n`. All bits are cleared
+    /// here, then set when a sector is erased and cleared again when it is
+    /// written to. Sectors past the end of the map are never considered
+    /// erased. This lets [`Flash::erase_if_needed`] skip redundant erases.
+    /// The map only reflects what this driver did, so it must not be used
+    /// while something else modifies the chip.
+    pub fn set_erase_map(&mut self, map: &'a mut [u8]) {
+        map.iter_mut().for_each(|byte| *byte = 0);
+        self.erase_map = Some(map);
+    }
+
+    /// Returns whether the sector containing `addr` is known to be erased,
+    /// according to the map set with [`Flash::set_erase_map`].
+    pub fn is_known_erased(&self, addr: u32) -> bool {
+        let sector = (addr / SECTOR_SIZE) as usize;
+        match self.erase_map.as_ref().and_then(|map| map.get(sector / 8)) {
+            Some(byte) => byte & (1 << (sector % 8)) != 0,
+            None => false,
+        }
+    }
+
+    /// Erases `amount` sectors starting with the one containing `addr`,
+    /// skipping sectors that are known to be erased already.
+    pub fn erase_if_needed(&mut self, addr: u32, amount: usize) -> Result<(), Error<SPI, CS>> {
+        let sector_size = self.sector_size;
+        let start = align_down(addr, sector_size);
+        self.ensure_in_bounds(start, amount as u64 * u64::from(sector_size))?;
+        for sector in 0..amount as u32 {
+            let addr = start + sector * sector_size;
+            // The map tracks 4 KiB units, which may be smaller than a sector.
+            let erased = (addr..addr + sector_size)
+                .step_by(SECTOR_SIZE as usize)
+                .all(|unit| self.is_known_erased(unit));
+            if !erased {
+                self.erase_sectors(addr, 1)?;
             }
-            self.cs.set_high().map_err(Error::Gpio)?;
-            spi_result.map(|_| ()).map_err(Error::Spi)?;
-            self.wait_done()?;
         }
         Ok(())
     }
 
-    fn erase_all(&mut self) -> Result<(), Error<SPI, CS>> {
-        self.write_enable()?;
-        let mut cmd_buf = [Opcode::ChipErase as u8];
-        self.command(&mut cmd_buf)?;
-        self.wait_done()?;
-        Ok(())
+    /// Updates the erase map for the sectors touching `range`.
+    fn mark_erased(&mut self, range: Range<u32>, erased: bool) {
+        if let Some(map) = &mut self.erase_map {
+            if range.start >= range.end {
+                return;
+            }
+            let first = (range.start / SECTOR_SIZE) as usize;
+            let last = ((range.end - 1) / SECTOR_SIZE) as usize;
+            for sector in first..(last + 1).min(map.len() * 8) {
+                if erased {
+                    map[sector / 8] |= 1 << (sector % 8);
+                } else {
+                    map[sector / 8] &= !(1 << (sector % 8));
+                }
+            }
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Returns how long the most recent sector, block or chip erase took.
+    ///
+    /// Erase times grow as a chip wears out, so a sudden increase can be used
+    /// as an early warning. Returns `None` until the first erase completed.
+    pub fn last_erase_duration(&self) -> Option<EraseDuration> {
+        self.last_erase
+    }
 
-    #[test]
-    fn test_decode_jedec_id() {
-        let cypress_id_bytes = [0x7F, 0x7F, 0x7F, 0x7F, 0x7F, 0x7F, 0xC2, 0x22, 0x08];
-        let ident = Identification::from_jedec_id(&cypress_id_bytes);
-        assert_eq!(0xC2, ident.mfr_code());
-        assert_eq!(6, ident.continuation_count());
-        let device_id = ident.device_id();
-        assert_eq!(device_id[0], 0x22);
-        assert_eq!(device_id[1], 0x08);
+    /// Sets the clock used to time page programs and sector erases.
+    ///
+    /// Until a clock is set, no timings are recorded.
+    #[cfg(feature = "metrics")]
+    pub fn set_clock(&mut self, clock: &'a dyn Clock) {
+        self.clock = Some(clock);
+    }
+
+    /// Returns the timings recorded so far.
+    #[cfg(feature = "metrics")]
+    pub fn timings(&self) -> &Timings {
+        &self.timings
+    }
+
+    /// Discards all timings recorded so far.
+    #[cfg(feature = "metrics")]
+    pub fn reset_timings(&mut self) {
+        self.timings = Timings::default();
+    }
+
+    #[cfg(feature = "metrics")]
+    fn now(&self) -> Option<u64> {
+        self.clock.map(|clock| clock.now_micros())
+    }
+
+    /// Returns the time passed since `start`, if a clock is set.
+    #[cfg(feature = "metrics")]
+    fn elapsed(&self, start: Option<u64>) -> Option<u64> {
+        match (start, self.clock) {
+            (Some(start), Some(clock)) => Some(clock.now_micros().saturating_sub(start)),
+            _ => None,
+        }
+    }
+
+    /// Records the time passed since `start` into the statistics picked by
+    /// `stats`, and returns it.
+    #[cfg(feature = "metrics")]
+    fn record(&mut self, start: Option<u64>, stats: fn(&mut Timings) -> &mut Stats) -> Option<u64> {
+        let elapsed = self.elapsed(start);
+        if let Some(elapsed) = elapsed {
+            stats(&mut self.timings).record(elapsed);
+        }
+        elapsed
+    }
+
+    /// Runs `op` on the parts of an access of `buf.len()` bytes at `addr`
+    /// that lie inside the chip, according to the capacity and overflow mode.
+    ///
+    /// Empty accesses never reach the chip.
+    fn for_each_segment(
+        &mut self,
+        addr: u32,
+        mut buf: &mut [u8],
+        mut op: impl FnMut(&mut Self, u32, &mut [u8]) -> Result<(), Error<SPI, CS>>,
+    ) -> Result<(), Error<SPI, CS>> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.ensure_idle()?;
+
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return op(self, addr, buf),
+        };
+
+        let mut addr = match self.overflow {
+            Overflow::Error => {
+                if u64::from(addr) + buf.len() as u64 > u64::from(capacity) {
+                    return Err(Error::OutOfBounds);
+                }
+                addr
+            }
+            // Wrapping around more than once would overwrite the start of the
+            // access with its own end.
+            Overflow::Wrap if buf.len() > capacity as usize => {
+                return Err(Error::OutOfBounds);
+            }
+            Overflow::Wrap => addr % capacity,
+        };
+
+        loop {
+            let len = buf.len().min((capacity - addr) as usize);
+            let (head, tail) = buf.split_at_mut(len);
+            op(self, addr, head)?;
+            if tail.is_empty() {
+                return Ok(());
+            }
+            buf = tail;
+            addr = 0;
+        }
+    }
+
+    /// Returns the SPI master, the chip-select pin, and the delays to insert
+    /// around selecting the chip, for running a transaction.
+    fn bus(&mut self) -> (&mut SPI, &mut CS, Option<CsDelay<'_>>) {
+        let cs_delay = match &mut self.delay {
+            Some(delay) if self.cs_setup_us != 0 || self.cs_hold_us != 0 => Some(CsDelay {
+                delay,
+                setup_us: self.cs_setup_us,
+                hold_us: self.cs_hold_us,
+            }),
+            _ => None,
+        };
+        (&mut self.spi, &mut self.cs, cs_delay)
+    }
+
+    fn command(&mut self, bytes: &mut [u8]) -> Result<(), Error<SPI, CS>> {
+        let (spi, cs, cs_delay) = self.bus();
+        spi_command(spi, cs, cs_delay, &mut [bytes])
+    }
+
+    /// Executes an arbitrary command on the chip.
+    ///
+    /// This is an escape hatch for vendor-specific commands that this driver
+    /// doesn't support itself. The chip is selected for the whole command, and
+    /// deselected again even if the SPI transfer fails.
+    ///
+    /// # Parameters
+    ///
+    /// * `opcode`: The instruction byte to send.
+    /// * `addr`: A 24-bit address to send after the opcode, if the command
+    ///   takes one.
+    /// * `dummy`: The number of dummy bytes to clock out after the address.
+    /// * `data`: Sent to the chip after the dummy bytes, and overwritten with
+    ///   the bytes received at the same time.
+    pub fn exec(
+        &mut self,
+        opcode: u8,
+        addr: Option<u32>,
+        dummy: u8,
+        data: &mut [u8],
+    ) -> Result<(), Error<SPI, CS>> {
+        let (spi, cs, cs_delay) = self.bus();
+        framed_command(spi, cs, cs_delay, opcode, addr, dummy, data)
+    }
+
+    /// Reads the JEDEC manufacturer/device identification.
+    pub fn read_jedec_id(&mut self) -> Result<Identification, Error<SPI, CS>> {
+        // Optimistically read 12 bytes, even though some identifiers will be shorter
+        let mut buf: [u8; 12] = [0; 12];
+        buf[0] = Opcode::ReadJedecId as u8;
+        self.command(&mut buf)?;
+
+        // Skip buf[0] (SPI read response byte)
+        Ok(Identification::from_jedec_id(&buf[1..]))
+    }
+
+    /// Determines which optional features the chip supports.
+    ///
+    /// The JEDEC ID is looked up in a table of known chip families, and the
+    /// result is extended with what the chip's SFDP table reports. For
+    /// unknown chips without SFDP, this returns no capabilities at all.
+    pub fn capabilities(&mut self) -> Result<Capabilities, Error<SPI, CS>> {
+        let id = self.read_jedec_id()?;
+        let mut caps = Capabilities::from_jedec_id(&id).unwrap_or_else(Capabilities::empty);
+        if let Some(params) = SfdpParams::read(self)? {
+            caps |= Capabilities::SFDP;
+            if params.address_bytes != AddressBytes::Three {
+                caps |= Capabilities::FOUR_BYTE_ADDRESS;
+            }
+            let reads = params.fast_reads;
+            if reads.dual_output || reads.dual_io {
+                caps |= Capabilities::DUAL;
+            }
+            if reads.quad_output || reads.quad_io {
+                caps |= Capabilities::QUAD;
+            }
+        }
+        Ok(caps)
+    }
+
+    /// Reads the 8-bit manufacturer ID and device ID (opcode 0x90).
+    ///
+    /// This is the legacy alternative to [`Flash::read_jedec_id`] that older
+    /// chips and some bootloaders use. The device ID is the same as the one
+    /// returned by [`PoweredDown::release_power_down`].
+    pub fn read_device_id(&mut self) -> Result<(u8, u8), Error<SPI, CS>> {
+        let mut ids = [0; 2];
+        self.exec(Opcode::ReadMfDId as u8, Some(0), 0, &mut ids)?;
+        Ok((ids[0], ids[1]))
+    }
+
+    /// Reads the 64-bit unique ID programmed into the chip at the factory
+    /// (opcode 0x4B).
+    ///
+    /// Supported by Winbond and most compatible chips. Others may return
+    /// garbage, or all `0xFF`.
+    pub fn read_unique_id(&mut self) -> Result<[u8; 8], Error<SPI, CS>> {
+        let mut id = [0; 8];
+        self.exec(Opcode::ReadUniqueId as u8, None, 4, &mut id)?;
+        Ok(id)
+    }
+
+    /// Reads SFDP data starting at `addr` into `buf`.
+    ///
+    /// See the [`sfdp`](crate::sfdp) module for parsing it.
+    pub fn read_sfdp(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<SPI, CS>> {
+        self.exec(Opcode::ReadSfdp as u8, Some(addr), 1, buf)
+    }
+
+    /// Puts the chip into deep power-down mode.
+    ///
+    /// In this mode the chip draws very little current, but ignores all
+    /// commands except Release Power-Down. The driver is kept in the returned
+    /// [`PoweredDown`] until [`PoweredDown::release_power_down`] wakes the
+    /// chip up again, so it can't be used for anything else meanwhile.
+    ///
+    /// On failure, the driver is handed back along with the error. This
+    /// includes [`Error::Busy`] while an operation started with one of the
+    /// `start_*` methods is pending, since the chip wouldn't power down then.
+    // There's no heap to box the driver in.
+    #[allow(clippy::result_large_err, clippy::type_complexity)]
+    pub fn power_down(mut self) -> Result<PoweredDown<'a, SPI, CS, D>, (Self, Error<SPI, CS>)> {
+        let mut cmd_buf = [Opcode::PowerDown as u8];
+        match self.ensure_idle().and_then(|_| self.command(&mut cmd_buf)) {
+            Ok(()) => Ok(PoweredDown { flash: self }),
+            Err(e) => Err((self, e)),
+        }
+    }
+
+    /// Sends Release Power-Down and returns the 8-bit legacy device ID, see
+    /// [`PoweredDown::release_power_down`].
+    fn release_power_down(&mut self) -> Result<u8, Error<SPI, CS>> {
+        let mut id = [0];
+        self.exec(Opcode::ReadDeviceId as u8, None, 3, &mut id)?;
+        if let Some(delay) = &mut self.delay {
+            delay.delay_us(RELEASE_POWER_DOWN_US);
+        }
+        Ok(id[0])
+    }
+
+    /// Reads the contents of `range` into a newly allocated `Vec`.
+    ///
+    /// This is only available with the `alloc` Cargo feature.
+    #[cfg(feature = "alloc")]
+    pub fn dump_region(
+        &mut self,
+        range: Range<u32>,
+    ) -> Result<alloc::vec::Vec<u8>, Error<SPI, CS>> {
+        let len = range.end.saturating_sub(range.start) as usize;
+        self.read_to_vec(range.start, len)
+    }
+
+    /// Sets whether every read is done twice and compared, for noisy buses.
+    ///
+    /// With `Some(retries)`, reads whose two copies disagree are repeated up
+    /// to `retries` times before failing with [`Error::Mismatch`]. `None`, the
+    /// default, reads everything once. This only affects [`Read::read`], not
+    /// [`Flash::fast_read`].
+    pub fn set_paranoid_read(&mut self, retries: Option<u8>) {
+        self.paranoid_read = retries;
+    }
+
+    /// Reads flash contents into `buf` with the Fast Read command.
+    ///
+    /// Plain reads are limited to a lower clock rate (often around 50 MHz),
+    /// while Fast Read works up to the chip's maximum clock, at the cost of
+    /// some dummy cycles after the address. Otherwise this behaves like
+    /// [`Read::read`].
+    pub fn fast_read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<SPI, CS>> {
+        self.for_each_segment(addr, buf, |this, addr, buf| {
+            let dummy = this.fast_read_dummy;
+            let (spi, cs, cs_delay) = this.bus();
+            framed_command(
+                spi,
+                cs,
+                cs_delay,
+                Opcode::FastRead as u8,
+                Some(addr),
+                dummy,
+                buf,
+            )
+        })
+    }
+
+    /// Sets the number of dummy bytes [`Flash::fast_read`] sends after the
+    /// address. Defaults to 1.
+    ///
+    /// Each byte is 8 dummy clock cycles. Some chips can be configured to
+    /// need more of them at high clock rates.
+    pub fn set_fast_read_dummy(&mut self, bytes: u8) {
+        self.fast_read_dummy = bytes;
+    }
+
+    /// Reads `len` bytes starting at `addr` through the buffer `chunk`,
+    /// calling `f` with the address and contents of every chunk read.
+    ///
+    /// The same buffer is reused for every chunk, so it can be a DMA buffer
+    /// that has to live in a particular memory region. The last chunk is
+    /// shorter than `chunk` if `len` is not a multiple of its length.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk` is empty and `len` is not 0.
+    pub fn read_chunks<F>(
+        &mut self,
+        addr: u32,
+        len: u32,
+        chunk: &mut [u8],
+        mut f: F,
+    ) -> Result<(), Error<SPI, CS>>
+    where
+        F: FnMut(u32, &[u8]),
+    {
+        assert!(len == 0 || !chunk.is_empty(), "empty chunk buffer");
+
+        let mut offset = 0;
+        while offset < len {
+            let n = chunk.len().min((len - offset) as usize);
+            let current_addr = addr.wrapping_add(offset);
+            self.read(current_addr, &mut chunk[..n])?;
+            f(current_addr, &chunk[..n]);
+            offset += n as u32;
+        }
+        Ok(())
+    }
+
+    /// Feeds the `len` bytes starting at `addr` into `digest`.
+    pub fn digest<G: Digest>(
+        &mut self,
+        addr: u32,
+        len: u32,
+        digest: &mut G,
+    ) -> Result<(), Error<SPI, CS>> {
+        let mut buf = [0; 64];
+        self.read_chunks(addr, len, &mut buf, |_, chunk| digest.update(chunk))
+    }
+
+    /// Programs `len` bytes starting at `addr` with `value`.
+    ///
+    /// Like [`BlockDevice::write_bytes`], this doesn't erase anything first.
+    /// Since programming can only clear bits, filling with `0xFF` does
+    /// nothing and doesn't access the chip.
+    pub fn fill(&mut self, addr: u32, len: u32, value: u8) -> Result<(), Error<SPI, CS>> {
+        if value == 0xFF {
+            return Ok(());
+        }
+
+        let mut buf = [value; PAGE_SIZE];
+        let mut offset = 0;
+        while offset < len {
+            let current_addr = addr + offset;
+            let n = (PAGE_SIZE as u32 - current_addr % PAGE_SIZE as u32).min(len - offset);
+            // The previous write overwrote the buffer with what the chip sent.
+            buf.iter_mut().for_each(|byte| *byte = value);
+            self.write_bytes(current_addr, &mut buf[..n as usize])?;
+            offset += n;
+        }
+        Ok(())
+    }
+
+    /// Tests the chip by erasing, programming and verifying one sector.
+    ///
+    /// The sector containing `addr` is erased, programmed with a
+    /// pseudorandom pattern, read back, and erased again. Whatever it
+    /// contained before is lost. A difference in the read-back contents fails
+    /// with [`Error::Mismatch`].
+    ///
+    /// On success, the returned report tells how long erasing, programming
+    /// and verifying took. With the `metrics` feature, the durations of the
+    /// erases and programs are also recorded in [`Flash::timings`] like those
+    /// of any other operation.
+    pub fn self_test(&mut self, addr: u32) -> Result<SelfTestReport, Error<SPI, CS>> {
+        fn pattern(addr: u32) -> u8 {
+            (addr.wrapping_mul(0x9E37_79B1) >> 24) as u8
+        }
+
+        let start = align_down(addr, self.sector_size);
+        let mut buf = [0; PAGE_SIZE];
+        self.erase_sectors(start, 1)?;
+        let erase = self.last_erase.expect("erase duration not recorded");
+        let sector = start..start + self.sector_size;
+        self.verify_range(sector.clone(), &mut buf, |_| 0xFF)?;
+
+        #[cfg(feature = "metrics")]
+        let begin = self.now();
+        let mut program_polls = 0u32;
+        for page in sector.clone().step_by(PAGE_SIZE) {
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte = pattern(page + i as u32);
+            }
+            self.write_bytes(page, &mut buf)?;
+            program_polls = program_polls.saturating_add(self.polls);
+        }
+        #[cfg(feature = "metrics")]
+        let program_micros = self.elapsed(begin);
+        #[cfg(not(feature = "metrics"))]
+        let program_micros = None;
+
+        #[cfg(feature = "metrics")]
+        let begin = self.now();
+        self.verify_range(sector.clone(), &mut buf, pattern)?;
+        #[cfg(feature = "metrics")]
+        let verify_micros = self.elapsed(begin);
+        #[cfg(not(feature = "metrics"))]
+        let verify_micros = None;
+
+        self.erase_sectors(start, 1)?;
+        self.verify_range(sector, &mut buf, |_| 0xFF)?;
+        Ok(SelfTestReport {
+            erase,
+            program_polls,
+            program_micros,
+            verify_micros,
+        })
+    }
+
+    /// Determines the size of the chip by looking for mirrored contents.
+    ///
+    /// Chips only decode as many address bits as they need, so their contents
+    /// repeat at multiples of their size. This writes a marker at address 0
+    /// and looks for it at every power of two from 4 KiB to 16 MiB, which is
+    /// useful for chips with an unknown JEDEC ID. Each hit is confirmed by
+    /// erasing the marker again, so leftover data can't be mistaken for a
+    /// mirror.
+    ///
+    /// The first sector is erased in the process, and its contents are lost.
+    /// [`Flash::set_capacity`] is not called with the result.
+    pub fn detect_mirroring(&mut self) -> Result<u32, Error<SPI, CS>> {
+        const MARKER: [u8; 8] = *b"MIRROR\x5A\xA5";
+
+        let mut size = SECTOR_SIZE;
+        let mut buf = [0; 8];
+        let mut marked = false;
+        while size < 1 << 24 {
+            if !marked {
+                self.erase_sectors(0, 1)?;
+                buf = MARKER;
+                self.write_bytes(0, &mut buf)?;
+                marked = true;
+            }
+
+            // Bypass `Overflow` handling, the capacity may well be wrong.
+            self.read_raw(size, &mut buf)?;
+            if buf == MARKER {
+                self.erase_sectors(0, 1)?;
+                marked = false;
+                self.read_raw(size, &mut buf)?;
+                if buf.iter().all(|&byte| byte == 0xFF) {
+                    return Ok(size);
+                }
+            }
+            size <<= 1;
+        }
+
+        if marked {
+            self.erase_sectors(0, 1)?;
+        }
+        Ok(size)
+    }
+
+    /// Checks that `range` contains `expected(addr)` at every address,
+    /// reading it through `buf`.
+    fn verify_range(
+        &mut self,
+        range: Range<u32>,
+        buf: &mut [u8],
+        expected: fn(u32) -> u8,
+    ) -> Result<(), Error<SPI, CS>> {
+        for chunk in range.clone().step_by(buf.len()) {
+            let len = buf.len().min((range.end - chunk) as usize);
+            let buf = &mut buf[..len];
+            self.read(chunk, buf)?;
+            for (i, &byte) in buf.iter().enumerate() {
+                let addr = chunk + i as u32;
+                if byte != expected(addr) {
+                    return Err(Error::Mismatch { addr });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Securely wipes all sectors touching `range`.
+    ///
+    /// The sectors are erased, programmed with all zeros, and erased again,
+    /// and each step is verified by reading the sectors back. If a step
+    /// fails, the remaining steps are skipped, and the error says which step
+    /// it was.
+    pub fn secure_erase(&mut self, range: Range<u32>) -> Result<(), SecureEraseError<SPI, CS>> {
+        if range.start >= range.end {
+            return Ok(());
+        }
+
+        let start = align_down(range.start, self.sector_size);
+        let end = align_up(range.end, self.sector_size);
+        let sectors = start..end;
+        let mut buf = [0; PAGE_SIZE];
+        let fail = |phase| move |error| SecureEraseError { phase, error };
+
+        self.erase_range(start, end - start)
+            .and_then(|_| self.verify_range(sectors.clone(), &mut buf, |_| 0xFF))
+            .map_err(fail(WipePhase::Erase))?;
+        self.fill(start, end - start, 0x00)
+            .and_then(|_| self.verify_range(sectors.clone(), &mut buf, |_| 0x00))
+            .map_err(fail(WipePhase::Zero))?;
+        self.erase_range(start, end - start)
+            .and_then(|_| self.verify_range(sectors, &mut buf, |_| 0xFF))
+            .map_err(fail(WipePhase::FinalErase))
+    }
+
+    /// Erases all sectors touching `addr..addr + len`.
+    ///
+    /// Where the range covers whole 64 KiB or 32 KiB blocks larger than a
+    /// sector, they are erased with a single block erase each, which is much
+    /// faster than erasing their sectors one by one. The rest is erased
+    /// sector by sector.
+    pub fn erase_range(&mut self, addr: u32, len: u32) -> Result<(), Error<SPI, CS>> {
+        if len == 0 {
+            return Ok(());
+        }
+
+        let sector_size = self.sector_size;
+        let sector = u64::from(sector_size);
+        let mut current = u64::from(align_down(addr, sector_size));
+        let end = (u64::from(addr) + u64::from(len) + sector - 1) & !(sector - 1);
+        self.ensure_in_bounds(current as u32, end - current)?;
+        while current < end {
+            let fits = |size: u32| {
+                size > sector_size
+                    && current % u64::from(size) == 0
+                    && end - current >= u64::from(size)
+            };
+            let size = if fits(BLOCK64_SIZE) {
+                self.erase_block64(current as u32)?;
+                BLOCK64_SIZE
+            } else if fits(BLOCK32_SIZE) {
+                self.erase_block32(current as u32)?;
+                BLOCK32_SIZE
+            } else {
+                self.erase_sectors(current as u32, 1)?;
+                sector_size
+            };
+            current += u64::from(size);
+        }
+        Ok(())
+    }
+
+    /// Erases the 32 KiB block containing `addr`.
+    pub fn erase_block32(&mut self, addr: u32) -> Result<(), Error<SPI, CS>> {
+        self.erase_block(
+            Opcode::BlockErase32,
+            align_down(addr, BLOCK32_SIZE),
+            BLOCK32_SIZE,
+        )
+    }
+
+    /// Erases the 64 KiB block containing `addr`.
+    pub fn erase_block64(&mut self, addr: u32) -> Result<(), Error<SPI, CS>> {
+        self.erase_block(
+            Opcode::BlockErase64,
+            align_down(addr, BLOCK64_SIZE),
+            BLOCK64_SIZE,
+        )
+    }
+
+    /// Erases the block of `size` bytes at `start` with `opcode`.
+    fn erase_block(&mut self, opcode: Opcode, start: u32, size: u32) -> Result<(), Error<SPI, CS>> {
+        self.ensure_in_bounds(start, size.into())?;
+        self.ensure_unprotected(start..start + size)?;
+
+        #[cfg(feature = "metrics")]
+        let begin = self.now();
+        self.prepare_write(true)?;
+        let (spi, cs, cs_delay) = self.bus();
+        framed_command(spi, cs, cs_delay, opcode as u8, Some(start), 0, &mut [])?;
+        self.wait_complete()?;
+        #[cfg(feature = "metrics")]
+        let micros = self.record(begin, |timings| &mut timings.block_erase);
+        #[cfg(not(feature = "metrics"))]
+        let micros = None;
+        self.last_erase = Some(EraseDuration {
+            polls: self.polls,
+            micros,
+        });
+        for sector in (start..start + size).step_by(self.sector_size as usize) {
+            self.sector_erased(sector);
+        }
+        self.finish_write()
+    }
+
+    /// Erases the whole chip like [`BlockDevice::erase_all`], calling
+    /// `progress` with an estimated percentage while it runs.
+    ///
+    /// Chip erase can take tens of seconds, and the chip doesn't report how
+    /// far it got. Instead, the estimate is the time spent so far relative to
+    /// `typical_micros`, the typical chip erase time (tCE) from the
+    /// datasheet. The status is sampled every 10 ms using the delay set via
+    /// [`Flash::with_delay`]; without one, the time can't be measured and only
+    /// 0 and 100 are reported. The estimate stays at 99 until the chip is
+    /// done, and 100 is reported once it has been erased successfully.
+    pub fn erase_all_with_progress<F: FnMut(u8)>(
+        &mut self,
+        typical_micros: u32,
+        mut progress: F,
+    ) -> Result<(), Error<SPI, CS>> {
+        self.chip_erase(Some((typical_micros, &mut progress)))?;
+        progress(100);
+        Ok(())
+    }
+
+    /// Returns a handle to the part of the chip at `range`.
+    ///
+    /// All addresses passed to the handle are relative to `range.start`, and
+    /// accesses outside of `range` fail with [`Error::OutOfBounds`]. This
+    /// makes it possible to give a subsystem access to its own part of the
+    /// chip only.
+    pub fn region(&mut self, range: Range<u32>) -> Region<'_, 'a, SPI, CS, D> {
+        Region { flash: self, range }
+    }
+
+    /// Reads the status register.
+    pub fn read_status(&mut self) -> Result<Status, Error<SPI, CS>> {
+        Ok(Status::from_bits_truncate(self.read_status_register(1)?))
+    }
+
+    /// Reads the whole byte of status register 1, 2 or 3, including the bits
+    /// [`Status`], [`Status2`] and [`Status3`] don't know about.
+    pub(crate) fn read_status_register(&mut self, register: u8) -> Result<u8, Error<SPI, CS>> {
+        let opcode = match register {
+            1 => Opcode::ReadStatus,
+            2 => Opcode::ReadStatus2,
+            _ => Opcode::ReadStatus3,
+        };
+        let mut buf = [opcode as u8, 0];
+        self.command(&mut buf)?;
+
+        Ok(buf[1])
+    }
+
+    /// Writes `value` to status register 1, 2 or 3 as-is, waits for the
+    /// write to finish and returns the first status register.
+    ///
+    /// There are no checks whether the write had any effect.
+    pub(crate) fn write_status_register(
+        &mut self,
+        register: u8,
+        value: u8,
+    ) -> Result<Status, Error<SPI, CS>> {
+        let opcode = match register {
+            1 => Opcode::WriteStatus,
+            2 => Opcode::WriteStatus2,
+            _ => Opcode::WriteStatus3,
+        };
+        self.ensure_idle()?;
+        self.write_enable()?;
+        let mut cmd_buf = [opcode as u8, value];
+        self.command(&mut cmd_buf)?;
+        self.wait_done()
+    }
+
+    /// Reads the second status register (opcode 0x35).
+    ///
+    /// See [`Status2`] for which chips have it.
+    pub fn read_status2(&mut self) -> Result<Status2, Error<SPI, CS>> {
+        let mut buf = [Opcode::ReadStatus2 as u8, 0];
+        self.command(&mut buf)?;
+
+        Ok(Status2::from_bits_truncate(buf[1]))
+    }
+
+    /// Reads the third status register (opcode 0x15).
+    ///
+    /// See [`Status3`] for which chips have it.
+    pub fn read_status3(&mut self) -> Result<Status3, Error<SPI, CS>> {
+        let mut buf = [Opcode::ReadStatus3 as u8, 0];
+        self.command(&mut buf)?;
+
+        Ok(Status3::from_bits_truncate(buf[1]))
+    }
+
+    /// Reads the status register, returning [`nb::Error::WouldBlock`] while
+    /// the chip is busy.
+    ///
+    /// This allows firmware built around `nb` to wait for commands started
+    /// with [`Flash::exec`] from its main loop.
+    pub fn poll_status(&mut self) -> nb::Result<Status, Error<SPI, CS>> {
+        let status = self.read_status()?;
+        if status.contains(Status::BUSY) {
+            Err(nb::Error::WouldBlock)
+        } else {
+            Ok(status)
+        }
+    }
+
+    /// Returns whether the chip is busy with an erase or program operation.
+    pub fn is_busy(&mut self) -> Result<bool, Error<SPI, CS>> {
+        Ok(self.read_status()?.contains(Status::BUSY))
+    }
+
+    /// Starts erasing the sector containing `addr`, without waiting for it to
+    /// finish.
+    ///
+    /// [`Flash::poll_complete`] has to be polled until it returns `Ok` before
+    /// the driver is used for anything else. Until then, reads, writes,
+    /// erases and other `start_*` calls fail with [`Error::Busy`]. The erase
+    /// isn't recorded in [`Flash::last_erase_duration`] or the `metrics`
+    /// timings.
+    pub fn start_erase_sector(&mut self, addr: u32) -> Result<(), Error<SPI, CS>> {
+        self.ensure_idle()?;
+        let sector = align_down(addr, self.sector_size);
+        self.ensure_in_bounds(sector, self.sector_size.into())?;
+        self.ensure_unprotected(sector..sector + self.sector_size)?;
+        self.prepare_write(true)?;
+        let opcode = self.sector_erase_opcode() as u8;
+        let (spi, cs, cs_delay) = self.bus();
+        framed_command(spi, cs, cs_delay, opcode, Some(sector), 0, &mut [])?;
+        self.pending = Some(Pending::SectorErase(sector));
+        Ok(())
+    }
+
+    /// Starts erasing the whole chip, without waiting for it to finish.
+    ///
+    /// See [`Flash::start_erase_sector`] for how to complete the operation.
+    pub fn start_erase_all(&mut self) -> Result<(), Error<SPI, CS>> {
+        self.ensure_idle()?;
+        if let Some(capacity) = self.capacity {
+            self.ensure_unprotected(0..capacity)?;
+        }
+        self.prepare_write(true)?;
+        let mut cmd_buf = [Opcode::ChipErase as u8];
+        self.command(&mut cmd_buf)?;
+        self.pending = Some(Pending::ChipErase);
+        Ok(())
+    }
+
+    /// Starts programming `data` to `addr`, without waiting for it to
+    /// finish.
+    ///
+    /// See [`Flash::start_erase_sector`] for how to complete the operation.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `data` doesn't fit in the page containing `addr`, since a
+    /// single Page Program can't cross a page boundary.
+    pub fn start_write_page(&mut self, addr: u32, data: &mut [u8]) -> Result<(), Error<SPI, CS>> {
+        self.ensure_idle()?;
+        let page_size = self.page_size as usize;
+        assert!(
+            addr as usize % page_size + data.len() <= page_size,
+            "data crosses a page boundary"
+        );
+        self.ensure_in_bounds(addr, data.len() as u64)?;
+        self.ensure_unprotected(addr..addr + data.len() as u32)?;
+        self.mark_erased(addr..addr + data.len() as u32, false);
+        self.prepare_write(true)?;
+        let (spi, cs, cs_delay) = self.bus();
+        framed_command(
+            spi,
+            cs,
+            cs_delay,
+            Opcode::PageProg as u8,
+            Some(addr),
+            0,
+            data,
+        )?;
+        self.pending = Some(Pending::PageProgram);
+        Ok(())
+    }
+
+    /// Checks whether an operation started with one of the `start_*` methods
+    /// has finished.
+    ///
+    /// Returns `WouldBlock` while the chip is busy. Once it is done, this
+    /// runs the same checks and bookkeeping as the blocking methods. Returns
+    /// `Ok` right away if no operation was started.
+    pub fn poll_complete(&mut self) -> nb::Result<(), Error<SPI, CS>> {
+        if self.pending.is_none() {
+            return Ok(());
+        }
+        let status = self.poll_status()?;
+        let pending = self.pending.take();
+        self.check_complete(status)?;
+        match pending {
+            Some(Pending::SectorErase(sector)) => self.sector_erased(sector),
+            Some(Pending::ChipErase) => self.chip_erased(),
+            Some(Pending::PageProgram) | None => {}
+        }
+        self.finish_write()?;
+        Ok(())
+    }
+
+    /// Writes the `PROT` and `SRWD` bits of `status` to the status register
+    /// and checks that they took effect.
+    ///
+    /// The other bits of the register are read first and written back
+    /// unchanged, since many chips keep more settings there (eg. the
+    /// top/bottom and sector protection bits of Winbond chips, or BP3 and QE
+    /// on Macronix chips). If
+    /// they don't match and SRWD was set before, the write was most likely
+    /// blocked by a low `/WP` pin, which is reported as
+    /// [`Error::StatusLocked`]. Other mismatches are reported as
+    /// [`Error::UnexpectedStatus`]. Use [`Flash::write_status_with_wp`] if
+    /// the driver should take care of the pin.
+    pub fn write_status_checked(&mut self, status: Status) -> Result<(), Error<SPI, CS>> {
+        self.ensure_idle()?;
+        let mask = Status::PROT | Status::SRWD;
+        let raw = self.read_status_register(1)?;
+        let locked = raw & Status::SRWD.bits() != 0;
+        let keep = !(mask | Status::BUSY | Status::WEL).bits();
+
+        let written = self.write_status_register(1, raw & keep | (status & mask).bits())?;
+
+        if written & mask == status & mask {
+            Ok(())
+        } else if locked {
+            // The chip ignored the command, so the latch is still set.
+            self.write_disable()?;
+            Err(Error::StatusLocked)
+        } else {
+            Err(Error::UnexpectedStatus)
+        }
+    }
+
+    /// Reads the block protection settings from the status register.
+    pub fn get_protection(&mut self) -> Result<Protection, Error<SPI, CS>> {
+        Ok(self.read_status()?.into())
+    }
+
+    /// Writes the block protection settings to the status register, like
+    /// [`Flash::write_status_checked`].
+    ///
+    /// Only BP0-BP2 and SRWD are changed, all other status bits keep their
+    /// current values.
+    ///
+    /// Once `srwd` is set, further changes are blocked while `/WP` is low.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `protection.bp` is larger than 7.
+    pub fn set_protection(&mut self, protection: Protection) -> Result<(), Error<SPI, CS>> {
+        assert!(protection.bp <= 7, "BP value out of range");
+        self.write_status_checked(protection.status())
+    }
+
+    /// Like [`Flash::write_status_checked`], but drives the `/WP` pin high
+    /// for the write, so that a set SRWD bit doesn't block it.
+    ///
+    /// The pin is put back into its previous state afterwards, even if the
+    /// write fails. It has to use the same error type as the `CS` pin.
+    pub fn write_status_with_wp<WP>(
+        &mut self,
+        status: Status,
+        wp: &mut WP,
+    ) -> Result<(), Error<SPI, CS>>
+    where
+        WP: StatefulOutputPin<Error = CS::Error>,
+    {
+        let was_low = wp.is_set_low().map_err(Error::Gpio)?;
+        wp.set_high().map_err(Error::Gpio)?;
+        let result = self.write_status_checked(status);
+        if was_low {
+            wp.set_low().map_err(Error::Gpio)?;
+        }
+        result
+    }
+
+    /// Clears the write enable latch (opcode 0x04).
+    ///
+    /// The driver already does this after every erase or program with
+    /// [`WriteEnable::Once`], but applications can call it to
+    /// make sure the chip ignores writes from here on.
+    pub fn write_disable(&mut self) -> Result<(), Error<SPI, CS>> {
+        let mut cmd_buf = [Opcode::WriteDisable as u8];
+        self.command(&mut cmd_buf)?;
+        Ok(())
+    }
+
+    /// Returns whether the write enable latch is set, ie. whether the chip
+    /// would accept an erase or program command right now.
+    pub fn is_write_enabled(&mut self) -> Result<bool, Error<SPI, CS>> {
+        Ok(self.read_status()?.contains(Status::WEL))
+    }
+
+    /// Returns whether an erase or program operation is currently suspended.
+    ///
+    /// This reads the SUS bit from the second status register (opcode 0x35),
+    /// which is where Winbond and compatible chips keep it. On other chips,
+    /// this bit may mean something else entirely.
+    pub fn is_suspended(&mut self) -> Result<bool, Error<SPI, CS>> {
+        Ok(self.read_status2()?.contains(Status2::SUS))
+    }
+
+    fn write_enable(&mut self) -> Result<(), Error<SPI, CS>> {
+        let mut cmd_buf = [Opcode::WriteEnable as u8];
+        self.command(&mut cmd_buf)?;
+
+        if self.verify_write_enable && !self.read_status()?.contains(Status::WEL) {
+            return Err(Error::UnexpectedStatus);
+        }
+        Ok(())
+    }
+
+    /// Sets the write enable latch before an erase or program command, if the
+    /// write enable strategy requires it.
+    fn prepare_write(&mut self, first: bool) -> Result<(), Error<SPI, CS>> {
+        self.ensure_idle()?;
+        if first || self.write_enable == WriteEnable::Always {
+            self.write_enable()?;
+        }
+        Ok(())
+    }
+
+    /// Fails with [`Error::Busy`] while an operation started with one of the
+    /// `start_*` methods hasn't been completed yet.
+    fn ensure_idle(&self) -> Result<(), Error<SPI, CS>> {
+        match self.pending {
+            Some(_) => Err(Error::Busy),
+            None => Ok(()),
+        }
+    }
+
+    /// Clears the write enable latch after the last command of an operation,
+    /// if the write enable strategy requires it.
+    fn finish_write(&mut self) -> Result<(), Error<SPI, CS>> {
+        if self.write_enable == WriteEnable::Once {
+            self.write_disable()?;
+        }
+        Ok(())
+    }
+
+    /// Waits until the chip is no longer busy, and returns the final status.
+    ///
+    /// The number of status reads is left in `self.polls`. Between reads,
+    /// the driver waits according to [`Flash::set_polling`].
+    fn wait_done(&mut self) -> Result<Status, Error<SPI, CS>> {
+        self.polls = 0;
+        let polling = self.polling;
+        let mut interval = polling.interval_us;
+        let mut waited = 0_u64;
+        loop {
+            let status = self.read_status()?;
+            self.polls = self.polls.saturating_add(1);
+            if !status.contains(Status::BUSY) {
+                return Ok(status);
+            }
+
+            let delay = match &mut self.delay {
+                Some(delay) if interval > 0 => delay,
+                _ => continue,
+            };
+            if let Some(timeout) = polling.timeout_us {
+                if waited >= u64::from(timeout) {
+                    warn!("Flash: still busy after {} µs", waited);
+                    return Err(Error::Timeout);
+                }
+            }
+            delay.delay_us(interval);
+            waited += u64::from(interval);
+            interval = interval
+                .saturating_mul(2)
+                .min(polling.max_interval_us.max(polling.interval_us));
+        }
+    }
+
+    /// Waits for an erase or program command to finish, and checks that the
+    /// chip actually executed it if that is enabled.
+    fn wait_complete(&mut self) -> Result<(), Error<SPI, CS>> {
+        let status = self.wait_done()?;
+        self.check_complete(status)
+    }
+
+    /// Checks that the chip actually executed the erase or program command
+    /// that just finished with `status`, if that is enabled.
+    fn check_complete(&mut self, status: Status) -> Result<(), Error<SPI, CS>> {
+        // A suspended operation is not busy, but not done either.
+        if self.suspend_aware && self.is_suspended()? {
+            warn!("Flash: operation suspended, status = {:?}", status);
+            return Err(Error::UnexpectedStatus);
+        }
+
+        // The chip clears WEL when it completes a command, but leaves it set
+        // when it ignores the command (eg. because the target is protected).
+        if self.verify_completion
+            && self.write_enable == WriteEnable::Always
+            && status.contains(Status::WEL)
+        {
+            warn!("Flash: command not executed, status = {:?}", status);
+            return Err(Error::UnexpectedStatus);
+        }
+        Ok(())
+    }
+
+    /// Erases the whole chip, reporting progress to `progress` if given.
+    fn chip_erase(
+        &mut self,
+        progress: Option<(u32, &mut dyn FnMut(u8))>,
+    ) -> Result<(), Error<SPI, CS>> {
+        if let Some(capacity) = self.capacity {
+            self.ensure_unprotected(0..capacity)?;
+        }
+
+        #[cfg(feature = "metrics")]
+        let start = self.now();
+        self.prepare_write(true)?;
+        let mut cmd_buf = [Opcode::ChipErase as u8];
+        self.command(&mut cmd_buf)?;
+
+        let mut polls = 0;
+        if let Some((typical_micros, progress)) = progress {
+            progress(0);
+            let mut elapsed = 0_u64;
+            let mut reported = 0;
+            while self.read_status()?.contains(Status::BUSY) {
+                polls += 1;
+                if let Some(delay) = &mut self.delay {
+                    delay.delay_us(PROGRESS_INTERVAL_US);
+                    elapsed += u64::from(PROGRESS_INTERVAL_US);
+                }
+                let percent = (elapsed * 100 / u64::from(typical_micros.max(1))).min(99) as u8;
+                if percent != reported {
+                    reported = percent;
+                    progress(percent);
+                }
+            }
+        }
+
+        self.wait_complete()?;
+        #[cfg(feature = "metrics")]
+        let micros = self.elapsed(start);
+        #[cfg(not(feature = "metrics"))]
+        let micros = None;
+        self.last_erase = Some(EraseDuration {
+            polls: polls + self.polls,
+            micros,
+        });
+        self.chip_erased();
+        self.finish_write()
+    }
+
+    /// Updates the erase map and calls the erase hook after the sector at
+    /// `sector` was erased.
+    fn sector_erased(&mut self, sector: u32) {
+        self.mark_erased(sector..sector + self.sector_size, true);
+        if let Some(hook) = self.erase_hook {
+            hook(sector);
+        }
+    }
+
+    /// Updates the erase map and calls the erase hook after the whole chip
+    /// was erased.
+    fn chip_erased(&mut self) {
+        self.mark_erased(0..self.capacity.unwrap_or(!0), true);
+        if let (Some(hook), Some(capacity)) = (self.erase_hook, self.capacity) {
+            (0..capacity)
+                .step_by(self.sector_size as usize)
+                .for_each(hook);
+        }
+    }
+
+    fn read_raw(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<SPI, CS>> {
+        let mut mismatches = 0;
+        loop {
+            let (spi, cs, cs_delay) = self.bus();
+            framed_command(spi, cs, cs_delay, Opcode::Read as u8, Some(addr), 0, buf)?;
+            let retries = match self.paranoid_read {
+                Some(retries) => retries,
+                None => return Ok(()),
+            };
+            let bad = match self.compare_read(addr, buf)? {
+                Some(bad) => bad,
+                None => return Ok(()),
+            };
+            warn!("Flash: reads of {:#x} disagree", bad);
+            if mismatches == retries {
+                return Err(Error::Mismatch { addr: bad });
+            }
+            mismatches += 1;
+        }
+    }
+
+    /// Reads `expected.len()` bytes at `addr` again and returns the first
+    /// address whose contents differ from `expected`.
+    fn compare_read(&mut self, addr: u32, expected: &[u8]) -> Result<Option<u32>, Error<SPI, CS>> {
+        let mut buf = [0; 32];
+        for (i, expected) in expected.chunks(buf.len()).enumerate() {
+            let chunk_addr = addr + (i * buf.len()) as u32;
+            let buf = &mut buf[..expected.len()];
+            let (spi, cs, cs_delay) = self.bus();
+            framed_command(
+                spi,
+                cs,
+                cs_delay,
+                Opcode::Read as u8,
+                Some(chunk_addr),
+                0,
+                buf,
+            )?;
+            if let Some(offset) = buf.iter().zip(expected).position(|(a, b)| a != b) {
+                return Ok(Some(chunk_addr + offset as u32));
+            }
+        }
+        Ok(None)
+    }
+
+    fn write_raw(&mut self, addr: u32, data: &mut [u8]) -> Result<(), Error<SPI, CS>> {
+        self.ensure_unprotected(addr..addr + data.len() as u32)?;
+        self.mark_erased(addr..addr + data.len() as u32, false);
+
+        // A page program wraps around within the page, so every chunk has to
+        // end at a page boundary.
+        let page_size = self.page_size as usize;
+        let mut expected = [0; PAGE_SIZE];
+        let mut offset = 0;
+        while offset < data.len() {
+            #[cfg(feature = "metrics")]
+            let start = self.now();
+            self.prepare_write(offset == 0)?;
+
+            let current_addr: u32 = (addr as usize + offset).try_into().unwrap();
+            let mut len = (page_size - current_addr as usize % page_size).min(data.len() - offset);
+            if self.verify_writes {
+                len = len.min(PAGE_SIZE);
+            }
+            let chunk = &mut data[offset..offset + len];
+            offset += len;
+            // The transfer overwrites `chunk`, so keep a copy to compare with.
+            let expected = &mut expected[..len];
+            if self.verify_writes {
+                expected.copy_from_slice(chunk);
+            }
+            let (spi, cs, cs_delay) = self.bus();
+            framed_command(
+                spi,
+                cs,
+                cs_delay,
+                Opcode::PageProg as u8,
+                Some(current_addr),
+                0,
+                chunk,
+            )?;
+            self.wait_complete()?;
+            #[cfg(feature = "metrics")]
+            self.record(start, |timings| &mut timings.page_program);
+            if self.verify_writes {
+                if let Some(addr) = self.compare_read(current_addr, expected)? {
+                    warn!("Flash: write of {:#x} did not stick", addr);
+                    return Err(Error::Mismatch { addr });
+                }
+            }
+        }
+        self.finish_write()
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin, D: Delay> Read<u32, SPI, CS> for Flash<'_, SPI, CS, D> {
+    /// Reads flash contents into `buf`, starting at `addr`.
+    ///
+    /// Note that `addr` is not fully decoded: Flash chips will typically only
+    /// look at the lowest `N` bits needed to encode their size, which means
+    /// that the contents are "mirrored" to addresses that are a multiple of the
+    /// flash size. Only 24 bits of `addr` are transferred to the device in any
+    /// case, limiting the maximum size of 25-series SPI flash chips to 16 MiB.
+    ///
+    /// If the capacity of the chip was set with [`Flash::set_capacity`], reads
+    /// past the end of the chip are instead handled according to the
+    /// [`Overflow`] mode.
+    ///
+    /// # Parameters
+    ///
+    /// * `addr`: 24-bit address to start reading at.
+    /// * `buf`: Destination buffer to fill.
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<SPI, CS>> {
+        self.for_each_segment(addr, buf, Self::read_raw)
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin, D: Delay> BlockDevice<u32, SPI, CS>
+    for Flash<'_, SPI, CS, D>
+{
+    /// Erases `amount` sectors of [`Flash::sector_size`] bytes, starting with
+    /// the one containing `addr`.
+    fn erase_sectors(&mut self, addr: u32, amount: usize) -> Result<(), Error<SPI, CS>> {
+        let sector_size = self.sector_size();
+        let first = align_down(addr, sector_size);
+        if amount > 0 {
+            let len = amount as u64 * u64::from(sector_size);
+            self.ensure_in_bounds(first, len)?;
+            let end: u32 = (u64::from(first) + len)
+                .try_into()
+                .map_err(|_| Error::OutOfBounds)?;
+            self.ensure_unprotected(first..end)?;
+        }
+
+        for c in 0..amount as u32 {
+            #[cfg(feature = "metrics")]
+            let start = self.now();
+            self.prepare_write(c == 0)?;
+
+            let current_addr = first + c * sector_size;
+            let opcode = self.sector_erase_opcode() as u8;
+            let (spi, cs, cs_delay) = self.bus();
+            framed_command(spi, cs, cs_delay, opcode, Some(current_addr), 0, &mut [])?;
+            self.wait_complete()?;
+            #[cfg(feature = "metrics")]
+            let micros = self.record(start, |timings| &mut timings.sector_erase);
+            #[cfg(not(feature = "metrics"))]
+            let micros = None;
+            self.last_erase = Some(EraseDuration {
+                polls: self.polls,
+                micros,
+            });
+            self.sector_erased(current_addr);
+        }
+
+        if amount > 0 {
+            self.finish_write()?;
+        }
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, addr: u32, data: &mut [u8]) -> Result<(), Error<SPI, CS>> {
+        self.for_each_segment(addr, data, Self::write_raw)
+    }
+
+    fn erase_all(&mut self) -> Result<(), Error<SPI, CS>> {
+        self.chip_erase(None)
+    }
+}
+
+/// A 25-series Flash chip in deep power-down mode, created by
+/// [`Flash::power_down`].
+///
+/// The chip ignores everything but Release Power-Down in this mode, so that
+/// is all this type allows.
+#[derive(Debug)]
+pub struct PoweredDown<'a, SPI: Transfer<u8>, CS: OutputPin, D = NoDelay> {
+    flash: Flash<'a, SPI, CS, D>,
+}
+
+impl<'a, SPI: Transfer<u8>, CS: OutputPin, D: Delay> PoweredDown<'a, SPI, CS, D> {
+    /// Wakes the chip up and returns the driver along with the chip's 8-bit
+    /// legacy device ID.
+    ///
+    /// This sends the opcode followed by 3 dummy bytes and reads the ID, so it
+    /// can be used to confirm that the chip actually woke up. The chip needs
+    /// some time (tRES2, typically a few µs) after this before it accepts
+    /// other commands. With a delay set via [`Flash::with_delay`], this waits
+    /// 30 µs before returning, which covers common chips. Otherwise the
+    /// caller has to make sure that time passes.
+    ///
+    /// On failure, the chip is handed back along with the error, so waking
+    /// it up can be retried.
+    // There's no heap to box the driver in.
+    #[allow(clippy::result_large_err, clippy::type_complexity)]
+    pub fn release_power_down(
+        mut self,
+    ) -> Result<(Flash<'a, SPI, CS, D>, u8), (Self, Error<SPI, CS>)> {
+        match self.flash.release_power_down() {
+            Ok(id) => Ok((self.flash, id)),
+            Err(e) => Err((self, e)),
+        }
+    }
+}
+
+/// A part of a 25-series Flash chip, created by [`Flash::region`].
+#[derive(Debug)]
+pub struct Region<'r, 'a, SPI: Transfer<u8>, CS: OutputPin, D = NoDelay> {
+    flash: &'r mut Flash<'a, SPI, CS, D>,
+    range: Range<u32>,
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin, D: Delay> Region<'_, '_, SPI, CS, D> {
+    /// Returns the range of chip addresses covered by the region.
+    pub fn range(&self) -> Range<u32> {
+        self.range.clone()
+    }
+
+    /// Translates the region-relative range `addr..addr + len` to chip
+    /// addresses, failing if it does not fit into the region.
+    fn translate(&self, addr: u32, len: u64) -> Result<u32, Error<SPI, CS>> {
+        let size = u64::from(self.range.end.saturating_sub(self.range.start));
+        if u64::from(addr) + len > size {
+            return Err(Error::OutOfBounds);
+        }
+        Ok(self.range.start + addr)
+    }
+
+    /// Erases the `amount` sectors starting at chip address `start`.
+    fn erase(&mut self, start: u32, amount: u32) -> Result<(), Error<SPI, CS>> {
+        self.flash.erase_sectors(start, amount as usize)
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin, D: Delay> Read<u32, SPI, CS> for Region<'_, '_, SPI, CS, D> {
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<SPI, CS>> {
+        let addr = self.translate(addr, buf.len() as u64)?;
+        self.flash.read(addr, buf)
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin, D: Delay> BlockDevice<u32, SPI, CS>
+    for Region<'_, '_, SPI, CS, D>
+{
+    /// Erases `amount` sectors starting with the one containing `addr`.
+    ///
+    /// Fails with [`Error::OutOfBounds`] if any of the sectors is not
+    /// completely inside the region.
+    fn erase_sectors(&mut self, addr: u32, amount: usize) -> Result<(), Error<SPI, CS>> {
+        if amount == 0 {
+            return Ok(());
+        }
+
+        let sector_size = self.flash.sector_size();
+        let start = align_down(self.range.start.saturating_add(addr), sector_size);
+        let len = amount as u64 * u64::from(sector_size);
+        if start < self.range.start {
+            return Err(Error::OutOfBounds);
+        }
+        self.translate(start - self.range.start, len)?;
+        self.erase(start, amount as u32)
+    }
+
+    /// Erases every sector of the region.
+    ///
+    /// Fails with [`Error::OutOfBounds`] if the region does not start and end
+    /// on sector boundaries.
+    fn erase_all(&mut self) -> Result<(), Error<SPI, CS>> {
+        let sector_size = self.flash.sector_size();
+        if (self.range.start | self.range.end) & (sector_size - 1) != 0 {
+            return Err(Error::OutOfBounds);
+        }
+        let amount = self.range.end.saturating_sub(self.range.start) / sector_size;
+        self.erase(self.range.start, amount)
+    }
+
+    fn write_bytes(&mut self, addr: u32, data: &mut [u8]) -> Result<(), Error<SPI, CS>> {
+        let addr = self.translate(addr, data.len() as u64)?;
+        self.flash.write_bytes(addr, data)
+    }
+}
+
+/// A minimal read-only driver for 25-series SPI Flash chips.
+///
+/// Unlike [`Flash`], this does not talk to the chip when it is created and
+/// has no configuration, so it can be created in a `const` context. This is
+/// meant for small first-stage bootloaders that only need to read an image.
+#[derive(Debug)]
+pub struct Reader<SPI, CS> {
+    spi: SPI,
+    cs: CS,
+}
+
+impl<SPI, CS> Reader<SPI, CS> {
+    /// Creates a reader from an SPI master and the chip-select pin of the
+    /// flash chip.
+    pub const fn new(spi: SPI, cs: CS) -> Self {
+        Self { spi, cs }
+    }
+
+    /// Releases the SPI master and the chip-select pin, consuming the reader.
+    pub fn release(self) -> (SPI, CS) {
+        (self.spi, self.cs)
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> Read<u32, SPI, CS> for Reader<SPI, CS> {
+    /// Reads flash contents into `buf`, starting at `addr`.
+    ///
+    /// `addr` is passed to the chip as-is, see [`Flash::read`].
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<SPI, CS>> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let (spi, cs) = (&mut self.spi, &mut self.cs);
+        framed_command(spi, cs, None, Opcode::Read as u8, Some(addr), 0, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use core::cell::RefCell;
+    use std::rc::Rc;
+
+    /// A delay that only logs the requested delays.
+    #[derive(Debug, Clone, Default)]
+    struct LogDelay(Rc<RefCell<Vec<u32>>>);
+
+    impl LogDelay {
+        fn total(&self) -> u32 {
+            self.0.borrow().iter().sum()
+        }
+    }
+
+    impl embedded_hal::blocking::delay::DelayUs<u32> for LogDelay {
+        fn delay_us(&mut self, us: u32) {
+            self.0.borrow_mut().push(us);
+        }
+    }
+
+    #[test]
+    fn test_status_display() {
+        let status = Status::WEL | Status::from_bits_truncate(2 << 2);
+        assert_eq!(status.to_string(), "WEL BP=2");
+        assert_eq!(
+            status.describe(0x200000).to_string(),
+            "WEL BP=2 (protected: 0x1e0000..0x200000)"
+        );
+        assert_eq!(
+            (Status::BUSY | Status::SRWD).describe(0x200000).to_string(),
+            "BUSY BP=0 SRWD (nothing protected)"
+        );
+
+        assert_eq!(Status2::empty().to_string(), "-");
+        assert_eq!((Status2::QE | Status2::SUS).to_string(), "QE SUS");
+        assert_eq!(
+            Status2::from_bits_truncate(0b1000_1000).to_string(),
+            "LB=0b001 SUS"
+        );
+        assert_eq!(Status3::from_bits_truncate(0x64).to_string(), "WPS DRV=3");
+    }
+
+    #[test]
+    fn test_protection() {
+        assert_eq!(Protection::top(0x200000, 0), Protection::NONE);
+        assert_eq!(Protection::top(0x200000, 0x10000).bp, 1);
+        assert_eq!(Protection::top(0x200000, 0x10001).bp, 2);
+        assert_eq!(Protection::top(0x200000, 0x1F0000).bp, 6);
+        assert_eq!(
+            Protection { bp: 6, srwd: false }.protected_range(0x200000),
+            0..0x200000
+        );
+
+        let (chip, spi, cs) = mock::chip(0x200000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        assert_eq!(flash.get_protection().unwrap(), Protection::NONE);
+        let protection = Protection { bp: 3, srwd: true };
+        flash.set_protection(protection).unwrap();
+        assert_eq!(chip.borrow().status, 0b1000_1100);
+        assert_eq!(flash.get_protection().unwrap(), protection);
+        flash.set_protection(Protection::NONE).unwrap();
+        assert_eq!(chip.borrow().status, 0);
+
+        chip.borrow_mut().status = 0b0110_0000;
+        flash.set_protection(protection).unwrap();
+        assert_eq!(chip.borrow().status, 0b1110_1100);
+        flash.set_protection(Protection::NONE).unwrap();
+        assert_eq!(chip.borrow().status, 0b0110_0000);
+    }
+
+    #[test]
+    fn test_device_ids() {
+        let (_chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        assert_eq!(flash.read_device_id().unwrap(), (0xEF, 0x13));
+        assert_eq!(
+            flash.read_unique_id().unwrap(),
+            [0xD1, 0x65, 0x38, 0x70, 0x2B, 0x4C, 0x5A, 0x21]
+        );
+    }
+
+    #[test]
+    fn test_decode_jedec_id() {
+        let cypress_id_bytes = [0x7F, 0x7F, 0x7F, 0x7F, 0x7F, 0x7F, 0xC2, 0x22, 0x08];
+        let ident = Identification::from_jedec_id(&cypress_id_bytes);
+        assert_eq!(0xC2, ident.mfr_code());
+        assert_eq!(6, ident.continuation_count());
+        let device_id = ident.device_id();
+        assert_eq!(device_id[0], 0x22);
+        assert_eq!(device_id[1], 0x08);
+    }
+
+    #[test]
+    fn test_exec() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().memory[0x1234] = 0xAB;
+        let mut flash = Flash::init(spi, cs).unwrap();
+
+        let mut id = [0; 3];
+        flash.exec(0x9F, None, 0, &mut id).unwrap();
+        assert_eq!(id, chip.borrow().jedec_id);
+
+        let mut data = [0; 1];
+        flash.exec(0x0B, Some(0x1234), 1, &mut data).unwrap();
+        assert_eq!(
+            chip.borrow().transactions.last().unwrap(),
+            &[0x0B, 0x00, 0x12, 0x34, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_power_down() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+
+        flash.start_erase_sector(0).unwrap();
+        let mut flash = match flash.power_down() {
+            Err((flash, Error::Busy)) => flash,
+            other => panic!("unexpected result {:?}", other),
+        };
+        assert!(!chip.borrow().powered_down);
+        nb::block!(flash.poll_complete()).unwrap();
+
+        let powered_down = flash.power_down().unwrap();
+        assert!(chip.borrow().powered_down);
+        let (mut flash, id) = powered_down.release_power_down().unwrap();
+        assert_eq!(id, 0x13);
+        assert!(!chip.borrow().powered_down);
+        assert_eq!(flash.read_status().unwrap(), Status::empty());
+    }
+
+    #[test]
+    fn test_release_power_down_delay() {
+        let delay = LogDelay::default();
+        let (_chip, spi, cs) = mock::chip(0x10000);
+        let flash = Flash::init(spi, cs).unwrap().with_delay(delay.clone());
+
+        let powered_down = flash.power_down().unwrap();
+        powered_down.release_power_down().unwrap();
+        assert_eq!(delay.total(), RELEASE_POWER_DOWN_US);
+    }
+
+    #[test]
+    fn test_overflow_error() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        flash.set_capacity(0x10000);
+
+        let mut buf = [0; 2];
+        match flash.read(0xFFFF, &mut buf) {
+            Err(Error::OutOfBounds) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match flash.write_bytes(0x10000, &mut buf) {
+            Err(Error::OutOfBounds) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(chip.borrow().opcodes(), [0x05]);
+        flash.read(0xFFFE, &mut buf).unwrap();
+    }
+
+    #[test]
+    fn test_overflow_wrap() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        flash.set_capacity(0x10000);
+        flash.set_overflow(Overflow::Wrap);
+
+        flash.write_bytes(0xFFFF, &mut [0x11, 0x22]).unwrap();
+        assert_eq!(chip.borrow().memory[0xFFFF], 0x11);
+        assert_eq!(chip.borrow().memory[0], 0x22);
+
+        let mut buf = [0; 2];
+        flash.read(0x1FFFF, &mut buf).unwrap();
+        assert_eq!(buf, [0x11, 0x22]);
+    }
+
+    #[test]
+    fn test_empty_access() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+
+        flash.read(0, &mut []).unwrap();
+        flash.write_bytes(0, &mut []).unwrap();
+        flash.erase_sectors(0, 0).unwrap();
+        flash.set_capacity(0x10000);
+        flash.read(0x20000, &mut []).unwrap();
+        assert_eq!(chip.borrow().opcodes(), [0x05]);
+    }
+
+    #[test]
+    fn test_oversized_wrap() {
+        let (chip, spi, cs) = mock::chip(0x100);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        flash.set_capacity(0x100);
+        flash.set_overflow(Overflow::Wrap);
+
+        match flash.write_bytes(0x80, &mut [0; 0x101]) {
+            Err(Error::OutOfBounds) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(chip.borrow().opcodes(), [0x05]);
+    }
+
+    #[test]
+    fn test_write_enable_once() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().sticky_wel = true;
+        let mut flash = Flash::init(spi, cs).unwrap();
+        flash.set_write_enable(WriteEnable::Once);
+
+        flash.write_bytes(0, &mut [0; 0x120]).unwrap();
+        let mut opcodes = chip.borrow().opcodes();
+        opcodes.retain(|&opcode| opcode != 0x05);
+        assert_eq!(opcodes, [0x06, 0x02, 0x02, 0x04]);
+        assert_eq!(flash.read_status().unwrap(), Status::empty());
+    }
+
+    #[test]
+    fn test_verify_write_enable() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().ignore_write_enable = true;
+        let mut flash = Flash::init(spi, cs).unwrap();
+        flash.set_verify_write_enable(true);
+
+        match flash.erase_all() {
+            Err(Error::UnexpectedStatus) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(chip.borrow().opcodes(), [0x05, 0x06, 0x05]);
+    }
+
+    #[test]
+    fn test_verify_completion() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().protected = true;
+        let mut flash = Flash::init(spi, cs).unwrap();
+
+        flash.write_bytes(0, &mut [0; 4]).unwrap();
+        flash.set_verify_completion(true);
+        match flash.write_bytes(0, &mut [0; 4]) {
+            Err(Error::UnexpectedStatus) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(chip.borrow().memory[..4], [0xFF; 4]);
+    }
+
+    #[test]
+    fn test_protected_range() {
+        let capacity = 0x100000;
+        assert_eq!(
+            Status::empty().protected_range(capacity),
+            capacity..capacity
+        );
+        let bp1 = Status::from_bits_truncate(0b0000_0100);
+        assert_eq!(bp1.protected_range(capacity), 0xF0000..capacity);
+        let bp4 = Status::from_bits_truncate(0b0001_0000);
+        assert_eq!(bp4.protected_range(capacity), 0x80000..capacity);
+        let bp5 = Status::from_bits_truncate(0b0001_0100);
+        assert_eq!(bp5.protected_range(capacity), 0..capacity);
+        assert_eq!(Status::PROT.protected_range(capacity), 0..capacity);
+        assert_eq!(bp1.protected_range(0x1000000), 0xFC0000..0x1000000);
+    }
+
+    #[test]
+    fn test_check_protection() {
+        let (chip, spi, cs) = mock::chip(0x100000);
+        chip.borrow_mut().status = 0b0000_0100;
+        let mut flash = Flash::init(spi, cs).unwrap();
+        flash.set_capacity(0x100000);
+        flash.set_check_protection(true);
+
+        flash.write_bytes(0xEFFFF, &mut [0]).unwrap();
+        match flash.write_bytes(0xEFFFF, &mut [0, 0]) {
+            Err(Error::Protected { addr: 0xF0000 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match flash.erase_sectors(0xF0000, 1) {
+            Err(Error::Protected { addr: 0xF0000 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match flash.erase_all() {
+            Err(Error::Protected { addr: 0xF0000 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_suspend_aware() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        assert!(!flash.is_suspended().unwrap());
+
+        chip.borrow_mut().status2 = Status2::SUS.bits();
+        assert!(flash.is_suspended().unwrap());
+        flash.erase_sectors(0, 1).unwrap();
+        flash.set_suspend_aware(true);
+        match flash.erase_sectors(0, 1) {
+            Err(Error::UnexpectedStatus) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_erase_sectors_stride() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().memory.iter_mut().for_each(|b| *b = 0);
+        let mut flash = Flash::init(spi, cs).unwrap();
+
+        flash.erase_sectors(0x1234, 2).unwrap();
+        let memory = &chip.borrow().memory;
+        assert_eq!(memory[0xFFF], 0);
+        assert!(memory[0x1000..0x3000].iter().all(|&b| b == 0xFF));
+        assert_eq!(memory[0x3000], 0);
+    }
+
+    #[test]
+    fn test_detect_geometry() {
+        let (chip, spi, cs) = mock::chip(0x40000);
+        chip.borrow_mut().jedec_id = [0x20, 0x20, 0x12];
+        chip.borrow_mut().memory.iter_mut().for_each(|b| *b = 0);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        assert_eq!(flash.geometry(), None);
+
+        let geometry = flash.detect_geometry().unwrap().unwrap();
+        assert_eq!(geometry.sector_size, 0x10000);
+        assert_eq!(geometry.capacity, 0x40000);
+        assert_eq!(flash.geometry(), Some(geometry));
+
+        chip.borrow_mut().transactions.clear();
+        flash.erase_sectors(0x12345, 2).unwrap();
+        assert_eq!(
+            chip.borrow().opcodes(),
+            [0x06, 0xD8, 0x05, 0x05, 0x06, 0xD8, 0x05, 0x05]
+        );
+        let memory = &chip.borrow().memory;
+        assert_eq!(memory[0xFFFF], 0);
+        assert!(memory[0x10000..0x30000].iter().all(|&b| b == 0xFF));
+        assert_eq!(memory[0x30000], 0);
+    }
+
+    #[test]
+    fn test_set_geometry_page_size() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        let geometry = Geometry {
+            page_size: 128,
+            block_size: BLOCK32_SIZE,
+            ..Geometry::standard(0x10000)
+        };
+        flash.set_geometry(geometry);
+        assert_eq!(flash.geometry(), Some(geometry));
+
+        chip.borrow_mut().transactions.clear();
+        flash.write_bytes(0x40, &mut [0; 0x100]).unwrap();
+        let programs: Vec<_> = chip
+            .borrow()
+            .transactions
+            .iter()
+            .filter(|t| t[0] == 0x02)
+            .map(|t| t.len() - 4)
+            .collect();
+        assert_eq!(programs, [0x40, 0x80, 0x40]);
+    }
+
+    #[test]
+    fn test_write_latch() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().sticky_wel = true;
+        let mut flash = Flash::init(spi, cs).unwrap();
+        assert!(!flash.is_write_enabled().unwrap());
+
+        flash.write_bytes(0, &mut [0]).unwrap();
+        assert!(flash.is_write_enabled().unwrap());
+        flash.write_disable().unwrap();
+        assert!(!flash.is_write_enabled().unwrap());
+    }
+
+    #[test]
+    fn test_erase_out_of_bounds() {
+        let (chip, spi, cs) = mock::chip(0x20000);
+        chip.borrow_mut().memory.iter_mut().for_each(|b| *b = 0);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        flash.set_capacity(0x10000);
+        flash.set_overflow(Overflow::Wrap);
+
+        // `matches!` needs Rust 1.42.
+        #[allow(clippy::match_like_matches_macro)]
+        let out_of_bounds = |result| match result {
+            Err(Error::OutOfBounds) => true,
+            _ => false,
+        };
+        assert!(out_of_bounds(flash.erase_sectors(0xF000, 2)));
+        assert!(out_of_bounds(flash.erase_sectors(0x10000, 1)));
+        assert!(out_of_bounds(flash.erase_range(0x8000, 0x9000)));
+        assert!(out_of_bounds(flash.erase_block64(0x10000)));
+        assert!(out_of_bounds(flash.erase_if_needed(0xF000, 2)));
+        assert!(out_of_bounds(flash.start_erase_sector(0x10000)));
+        assert!(out_of_bounds(flash.start_write_page(0x10000, &mut [0])));
+        assert!(chip.borrow().memory.iter().all(|&b| b == 0));
+
+        flash.erase_sectors(0xF000, 1).unwrap();
+        flash.erase_range(0, 0x10000).unwrap();
+        assert!(chip.borrow().memory[..0x10000].iter().all(|&b| b == 0xFF));
+        assert!(chip.borrow().memory[0x10000..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        assert_eq!(
+            flash.capabilities().unwrap(),
+            Capabilities::DUAL
+                | Capabilities::QUAD
+                | Capabilities::SUSPEND
+                | Capabilities::SECURITY_REGISTERS
+                | Capabilities::INDIVIDUAL_LOCK
+        );
+
+        chip.borrow_mut().jedec_id = [0x20, 0xBA, 0x19];
+        assert!(flash
+            .capabilities()
+            .unwrap()
+            .contains(Capabilities::FOUR_BYTE_ADDRESS | Capabilities::SUSPEND));
+
+        chip.borrow_mut().jedec_id = [0x12, 0x34, 0x14];
+        assert_eq!(flash.capabilities().unwrap(), Capabilities::empty());
+    }
+
+    #[test]
+    fn test_verify_writes() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        flash.set_verify_writes(true);
+
+        let mut data = [0x55; 300];
+        flash.write_bytes(0x80, &mut data).unwrap();
+        assert!(chip.borrow().memory[0x80..0x80 + 300]
+            .iter()
+            .all(|&b| b == 0x55));
+
+        // Programming can't set bits, so this doesn't read back as written.
+        match flash.write_bytes(0x100, &mut [0x55, 0xAA]) {
+            Err(Error::Mismatch { addr: 0x101 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_cs_delays() {
+        let delay = LogDelay::default();
+        let (_chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        flash.set_cs_delays(1, 2);
+        flash.read(0, &mut [0; 4]).unwrap();
+
+        let mut flash = flash.with_delay(delay.clone());
+        flash.read(0, &mut [0; 4]).unwrap();
+        flash.read_status().unwrap();
+        assert_eq!(delay.total(), 6);
+    }
+
+    #[test]
+    fn test_polling() {
+        let delay = LogDelay::default();
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap().with_delay(delay.clone());
+        flash.set_polling(Polling {
+            interval_us: 10,
+            max_interval_us: 40,
+            timeout_us: None,
+        });
+
+        chip.borrow_mut().busy_polls = 5;
+        flash.erase_sectors(0, 1).unwrap();
+        assert_eq!(*delay.0.borrow(), [10, 20, 40, 40, 40]);
+
+        delay.0.borrow_mut().clear();
+        flash.set_polling(Polling {
+            timeout_us: Some(100),
+            ..flash.polling
+        });
+        chip.borrow_mut().busy_polls = 100;
+        match flash.erase_sectors(0, 1) {
+            Err(Error::Timeout) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(*delay.0.borrow(), [10, 20, 40, 40]);
+    }
+
+    #[test]
+    fn test_paranoid_read() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        let mut buf = [0; 40];
+
+        chip.borrow_mut().flip_reads = 1;
+        flash.read(0x100, &mut buf).unwrap();
+        assert_eq!(buf[0], 0xFE);
+
+        flash.set_paranoid_read(Some(1));
+        chip.borrow_mut().flip_reads = 1;
+        flash.read(0x100, &mut buf).unwrap();
+        assert_eq!(buf, [0xFF; 40]);
+
+        chip.borrow_mut().flip_reads = 4;
+        match flash.read(0x100, &mut buf) {
+            Err(Error::Mismatch { addr: 0x100 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_fast_read() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().memory[0x100..0x104].copy_from_slice(&[1, 2, 3, 4]);
+        let mut flash = Flash::init(spi, cs).unwrap();
+
+        let mut buf = [0; 4];
+        flash.fast_read(0x100, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+        assert_eq!(chip.borrow().transactions.last().unwrap().len(), 4 + 1 + 4);
+
+        chip.borrow_mut().fast_read_dummy = 2;
+        flash.set_fast_read_dummy(2);
+        flash.fast_read(0x101, &mut buf[..2]).unwrap();
+        assert_eq!(buf[..2], [2, 3]);
+        assert_eq!(chip.borrow().opcodes().last(), Some(&0x0B));
+    }
+
+    #[test]
+    fn test_read_chunks() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().memory[..10].copy_from_slice(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        let mut flash = Flash::init(spi, cs).unwrap();
+
+        let mut chunks = Vec::new();
+        let mut buf = [0; 4];
+        flash
+            .read_chunks(0, 10, &mut buf, |addr, data| {
+                chunks.push((addr, data.to_vec()))
+            })
+            .unwrap();
+        assert_eq!(
+            chunks,
+            [
+                (0, vec![0, 1, 2, 3]),
+                (4, vec![4, 5, 6, 7]),
+                (8, vec![8, 9]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_typed() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().memory[0x100..0x108].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut flash = Flash::init(spi, cs).unwrap();
+
+        assert_eq!(flash.read_u32_le(0x100).unwrap(), 0x0403_0201);
+        #[cfg(feature = "bytemuck")]
+        assert_eq!(
+            flash.read_struct::<[u16; 4]>(0x100).unwrap(),
+            [0x0201, 0x0403, 0x0605, 0x0807]
+        );
+    }
+
+    #[test]
+    fn test_write_across_pages() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+
+        flash.write_bytes(0xFE, &mut [1, 2, 3, 4]).unwrap();
+        assert_eq!(
+            chip.borrow().memory[0xFC..0x104],
+            [0xFF, 0xFF, 1, 2, 3, 4, 0xFF, 0xFF]
+        );
+        assert_eq!(chip.borrow().memory[0], 0xFF);
+    }
+
+    #[test]
+    fn test_reader() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().memory[0x10..0x14].copy_from_slice(&[1, 2, 3, 4]);
+        let mut reader = Reader::new(spi, cs);
+
+        let mut buf = [0; 4];
+        reader.read(0x10, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+        assert_eq!(chip.borrow().opcodes(), [0x03]);
+    }
+
+    #[test]
+    fn test_self_test() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().memory[0x2345] = 0;
+        let mut flash = Flash::init(spi, cs).unwrap();
+
+        chip.borrow_mut().busy_polls = 2;
+        let report = flash.self_test(0x2345).unwrap();
+        assert!(chip.borrow().memory.iter().all(|&b| b == 0xFF));
+        assert_eq!(report.erase.polls, 3);
+        assert_eq!(report.program_polls, 16 * 3);
+        assert_eq!(report.verify_micros, None);
+
+        chip.borrow_mut().protected = true;
+        match flash.self_test(0x2000) {
+            Err(Error::Mismatch { addr: 0x2000 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_erase_hook() {
+        use core::sync::atomic::{AtomicU32, Ordering};
+
+        static COUNT: AtomicU32 = AtomicU32::new(0);
+        static SECTORS: AtomicU32 = AtomicU32::new(0);
+
+        let (_chip, spi, cs) = mock::chip(0x4000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        flash.set_erase_hook(|addr| {
+            COUNT.fetch_add(1, Ordering::Relaxed);
+            SECTORS.fetch_or(1 << (addr / SECTOR_SIZE), Ordering::Relaxed);
+        });
+
+        flash.erase_sectors(0x1234, 1).unwrap();
+        assert_eq!(SECTORS.load(Ordering::Relaxed), 0b0010);
+        flash.erase_all().unwrap();
+        assert_eq!(COUNT.load(Ordering::Relaxed), 1);
+        flash.set_capacity(0x4000);
+        flash.erase_all().unwrap();
+        assert_eq!(COUNT.load(Ordering::Relaxed), 5);
+        assert_eq!(SECTORS.load(Ordering::Relaxed), 0b1111);
+    }
+
+    #[test]
+    fn test_region() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().memory = vec![0; 0x10000];
+        let mut flash = Flash::init(spi, cs).unwrap();
+        let mut region = flash.region(0x2000..0x4000);
+
+        region.write_bytes(0x10, &mut [0]).unwrap();
+        let mut buf = [0xAA; 2];
+        region.read(0x1FFE, &mut buf).unwrap();
+        for result in &[
+            region.read(0x1FFF, &mut buf),
+            region.write_bytes(0x2000, &mut [0]),
+            region.erase_sectors(0x1000, 2),
+        ] {
+            match result {
+                Err(Error::OutOfBounds) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+
+        region.erase_sectors(0x1234, 1).unwrap();
+        assert_eq!(chip.borrow().memory[0x2FFF], 0x00);
+        assert!(chip.borrow().memory[0x3000..0x4000]
+            .iter()
+            .all(|&b| b == 0xFF));
+        region.erase_all().unwrap();
+        assert!(chip.borrow().memory[0x2000..0x4000]
+            .iter()
+            .all(|&b| b == 0xFF));
+        assert_eq!(chip.borrow().memory[0x1FFF], 0x00);
+        assert_eq!(chip.borrow().memory[0x4000], 0x00);
+
+        match flash.region(0x2100..0x4000).erase_all() {
+            Err(Error::OutOfBounds) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_erase_map() {
+        let mut map = [0xFF; 1];
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        flash.set_erase_map(&mut map);
+        assert!(!flash.is_known_erased(0));
+
+        flash.erase_if_needed(0x1000, 2).unwrap();
+        flash.erase_if_needed(0x1000, 3).unwrap();
+        flash.write_bytes(0x2FFF, &mut [0, 0]).unwrap();
+        flash.erase_if_needed(0x1000, 3).unwrap();
+        let erases = chip
+            .borrow()
+            .transactions
+            .iter()
+            .filter(|t| t[0] == 0x20)
+            .count();
+        assert_eq!(erases, 5);
+        assert!(flash.is_known_erased(0x1000));
+        assert!(!flash.is_known_erased(0x8000));
+
+        flash.erase_all().unwrap();
+        assert!(flash.is_known_erased(0x7000));
+        assert!(!flash.is_known_erased(0x8000));
+    }
+
+    #[test]
+    fn test_last_erase_duration() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        assert_eq!(flash.last_erase_duration(), None);
+
+        chip.borrow_mut().busy_polls = 5;
+        flash.erase_sectors(0, 1).unwrap();
+        let duration = flash.last_erase_duration().unwrap();
+        assert_eq!(duration.polls, 6);
+        assert_eq!(duration.micros, None);
+
+        chip.borrow_mut().busy_polls = 2;
+        flash.erase_all().unwrap();
+        assert_eq!(flash.last_erase_duration().unwrap().polls, 3);
+    }
+
+    #[test]
+    fn test_write_status_checked() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut wp = mock::Wp(chip.clone());
+        let mut flash = Flash::init(spi, cs).unwrap();
+
+        // Bits outside of `Status`, like TB on Winbond chips, are kept.
+        chip.borrow_mut().status = 1 << 6;
+        flash.write_status_checked(Status::PROT).unwrap();
+        assert_eq!(chip.borrow().status, 0b0101_1100);
+
+        let locked = Status::SRWD | Status::PROT;
+        flash.write_status_checked(locked).unwrap();
+        assert_eq!(flash.read_status().unwrap(), locked);
+
+        chip.borrow_mut().wp_low = true;
+        match flash.write_status_checked(Status::empty()) {
+            Err(Error::StatusLocked) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(flash.read_status().unwrap(), locked);
+
+        flash
+            .write_status_with_wp(Status::empty(), &mut wp)
+            .unwrap();
+        assert_eq!(flash.read_status().unwrap(), Status::empty());
+        assert!(chip.borrow().wp_low);
+    }
+
+    #[test]
+    fn test_poll_status() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().busy_polls = 2;
+        let mut flash = Flash::init(spi, cs).unwrap();
+
+        flash.exec(0x06, None, 0, &mut []).unwrap();
+        flash.exec(0x20, Some(0), 0, &mut []).unwrap();
+        match flash.poll_status() {
+            Err(nb::Error::WouldBlock) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match flash.poll_status() {
+            Err(nb::Error::WouldBlock) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(nb::block!(flash.poll_status()).unwrap(), Status::empty());
+    }
+
+    #[test]
+    fn test_detect_mirroring() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().memory[0x8000..0x8008].copy_from_slice(b"MIRROR\x5A\xA5");
+        let mut flash = Flash::init(spi, cs).unwrap();
+        flash.set_capacity(0x1000);
+        assert_eq!(flash.detect_mirroring().unwrap(), 0x10000);
+        assert!(chip.borrow().memory[..0x1000].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn test_erase_all_with_progress() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().busy_polls = 5;
+        let mut flash = Flash::init(spi, cs).unwrap();
+        let mut reports = Vec::new();
+        flash
+            .erase_all_with_progress(40_000, |percent| reports.push(percent))
+            .unwrap();
+        assert_eq!(reports, [0, 100]);
+
+        let mut flash = flash.with_delay(LogDelay::default());
+        reports.clear();
+        flash
+            .erase_all_with_progress(40_000, |percent| reports.push(percent))
+            .unwrap();
+        assert_eq!(reports, [0, 25, 50, 75, 99, 100]);
+        assert_eq!(flash.last_erase_duration().unwrap().polls, 6);
+    }
+
+    #[test]
+    fn test_non_blocking() {
+        let mut map = [0; 2];
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().busy_polls = 3;
+        chip.borrow_mut().memory[0x1000] = 0;
+        let mut flash = Flash::init(spi, cs).unwrap();
+        flash.set_erase_map(&mut map);
+
+        flash.start_erase_sector(0x1234).unwrap();
+        assert!(flash.is_busy().unwrap());
+        assert!(!flash.is_known_erased(0x1000));
+        match flash.poll_complete() {
+            Err(nb::Error::WouldBlock) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match flash.start_write_page(0x1010, &mut [1]) {
+            Err(Error::Busy) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match flash.read(0, &mut [0]) {
+            Err(Error::Busy) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        nb::block!(flash.poll_complete()).unwrap();
+        assert_eq!(chip.borrow().memory[0x1000], 0xFF);
+        assert!(flash.is_known_erased(0x1000));
+
+        flash.start_write_page(0x1010, &mut [1, 2, 3]).unwrap();
+        nb::block!(flash.poll_complete()).unwrap();
+        assert_eq!(chip.borrow().memory[0x1010..0x1013], [1, 2, 3]);
+        assert!(!flash.is_known_erased(0x1000));
+        assert!(!flash.is_busy().unwrap());
+
+        // Nothing pending, nothing to do.
+        flash.set_write_enable(WriteEnable::Once);
+        chip.borrow_mut().transactions.clear();
+        flash.poll_complete().unwrap();
+        assert!(chip.borrow().transactions.is_empty());
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn test_dump_region() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().memory[0x100..0x104].copy_from_slice(&[1, 2, 3, 4]);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        assert_eq!(flash.read_to_vec(0x101, 2).unwrap(), [2, 3]);
+        assert_eq!(flash.dump_region(0x100..0x104).unwrap(), [1, 2, 3, 4]);
+        assert!(flash.dump_region(0x100..0x100).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_erase_range() {
+        let (chip, spi, cs) = mock::chip(0x30000);
+        chip.borrow_mut().memory.iter_mut().for_each(|b| *b = 0);
+        let mut flash = Flash::init(spi, cs).unwrap();
+
+        flash.erase_range(0xF800, 0x10900).unwrap();
+        let erases: Vec<u8> = chip
+            .borrow()
+            .opcodes()
+            .into_iter()
+            .filter(|&op| op == 0x20 || op == 0x52 || op == 0xD8)
+            .collect();
+        assert_eq!(erases, [0x20, 0xD8, 0x20]);
+        {
+            let memory = &chip.borrow().memory;
+            assert_eq!(memory[0xEFFF], 0);
+            assert!(memory[0xF000..0x21000].iter().all(|&b| b == 0xFF));
+            assert_eq!(memory[0x21000], 0);
+        }
+
+        chip.borrow_mut().transactions.clear();
+        flash.erase_range(0x28000, 0x8000).unwrap();
+        assert!(chip.borrow().opcodes().contains(&0x52));
+    }
+
+    #[test]
+    fn test_fill() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+
+        flash.fill(0x1F0, 0x220, 0x00).unwrap();
+        let memory = &chip.borrow().memory;
+        assert_eq!(memory[0x1EF], 0xFF);
+        assert!(memory[0x1F0..0x410].iter().all(|&b| b == 0x00));
+        assert_eq!(memory[0x410], 0xFF);
+        assert_eq!(
+            chip.borrow()
+                .opcodes()
+                .iter()
+                .filter(|&&op| op == 0x02)
+                .count(),
+            4
+        );
+    }
+
+    #[test]
+    fn test_secure_erase() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        chip.borrow_mut().memory[0x1000..0x3000]
+            .iter_mut()
+            .for_each(|b| *b = 0x42);
+        let mut flash = Flash::init(spi, cs).unwrap();
+
+        flash.secure_erase(0x1800..0x2001).unwrap();
+        assert!(chip.borrow().memory[..0x3000].iter().all(|&b| b == 0xFF));
+        let programs = chip
+            .borrow()
+            .opcodes()
+            .iter()
+            .filter(|&&op| op == 0x02)
+            .count();
+        assert_eq!(programs, 0x2000 / 256);
+
+        chip.borrow_mut().protected = true;
+        chip.borrow_mut().memory[0x4000] = 0;
+        match flash.secure_erase(0x4000..0x5000) {
+            Err(SecureEraseError {
+                phase: WipePhase::Erase,
+                error: Error::Mismatch { addr: 0x4000 },
+            }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_release() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        flash.start_erase_sector(0).unwrap();
+        let mut flash = match flash.release() {
+            Err((flash, Error::Busy)) => flash,
+            other => panic!("unexpected result {:?}", other),
+        };
+        nb::block!(flash.poll_complete()).unwrap();
+
+        // Busy without the driver knowing, eg. after a failed call.
+        flash.exec(0x06, None, 0, &mut []).unwrap();
+        flash.exec(0x20, Some(0), 0, &mut []).unwrap();
+        let flash = match flash.release() {
+            Err((flash, Error::Busy)) => flash,
+            other => panic!("unexpected result {:?}", other),
+        };
+
+        let (spi, cs) = flash.release().unwrap();
+        assert!(Rc::ptr_eq(&spi.0, &chip));
+        assert!(Rc::ptr_eq(&cs.0, &chip));
     }
 }
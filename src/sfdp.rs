@@ -0,0 +1,251 @@
+//! Parsing of the Serial Flash Discoverable Parameters (SFDP, JESD216).
+//!
+//! Most chips made in the last decade describe themselves in an SFDP table,
+//! which makes it possible to configure the driver without knowing the chip.
+//! [`SfdpParams::read`] reads the JEDEC Basic Flash Parameter table and
+//! extracts the parts relevant to this crate.
+
+use crate::delay::Delay;
+use crate::series25::Flash;
+use crate::Error;
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+
+/// The `"SFDP"` signature at the start of the SFDP data.
+const SIGNATURE: [u8; 4] = *b"SFDP";
+/// The parameter ID of the Basic Flash Parameter table.
+const BASIC_ID: u16 = 0xFF00;
+/// Number of DWORDs of the Basic Flash Parameter table that are parsed.
+const BASIC_DWORDS: usize = 9;
+
+/// Number of address bytes the chip accepts.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AddressBytes {
+    /// 3-byte addresses only.
+    Three,
+    /// 3-byte addresses by default, 4-byte addresses after switching modes.
+    ThreeOrFour,
+    /// 4-byte addresses only.
+    Four,
+}
+
+/// An erase command supported by the chip.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EraseType {
+    /// Size of the erased area in bytes.
+    pub size: u32,
+    /// Opcode of the command.
+    pub opcode: u8,
+}
+
+/// Fast read modes supported by the chip, besides the single-line Fast
+/// Read every chip supports.
+///
+/// The names give the number of lines used for the opcode, the address and
+/// the data.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FastReads {
+    /// Dual Output Fast Read (1-1-2).
+    pub dual_output: bool,
+    /// Dual I/O Fast Read (1-2-2).
+    pub dual_io: bool,
+    /// Quad Output Fast Read (1-1-4).
+    pub quad_output: bool,
+    /// Quad I/O Fast Read (1-4-4).
+    pub quad_io: bool,
+}
+
+/// The chip properties read from the Basic Flash Parameter table.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SfdpParams {
+    /// Capacity of the chip in bytes.
+    pub capacity: u64,
+    /// Number of address bytes the chip accepts.
+    pub address_bytes: AddressBytes,
+    /// The supported erase commands, smallest first as listed in the table.
+    pub erase_types: [Option<EraseType>; 4],
+    /// The supported multi-line fast read modes.
+    pub fast_reads: FastReads,
+}
+
+impl SfdpParams {
+    /// Reads and parses the Basic Flash Parameter table of the chip.
+    ///
+    /// Returns `None` if the chip has no valid SFDP data, or the table is
+    /// too short.
+    pub fn read<SPI, CS, D>(
+        flash: &mut Flash<'_, SPI, CS, D>,
+    ) -> Result<Option<Self>, Error<SPI, CS>>
+    where
+        SPI: Transfer<u8>,
+        CS: OutputPin,
+        D: Delay,
+    {
+        let mut header = [0; 8];
+        flash.read_sfdp(0, &mut header)?;
+        if header[..4] != SIGNATURE {
+            return Ok(None);
+        }
+
+        // The number of parameter headers is stored minus one.
+        for i in 0..=u32::from(header[6]) {
+            let mut param = [0; 8];
+            flash.read_sfdp(8 + 8 * i, &mut param)?;
+            let id = u16::from(param[7]) << 8 | u16::from(param[0]);
+            let major = param[2];
+            let dwords = usize::from(param[3]);
+            if id != BASIC_ID || major != 1 {
+                continue;
+            }
+            if dwords < BASIC_DWORDS {
+                return Ok(None);
+            }
+
+            let ptr = u32::from(param[4]) | u32::from(param[5]) << 8 | u32::from(param[6]) << 16;
+            let mut table = [0; BASIC_DWORDS * 4];
+            flash.read_sfdp(ptr, &mut table)?;
+            return Ok(Some(Self::parse(&table)));
+        }
+        Ok(None)
+    }
+
+    /// Parses the first DWORDs of a Basic Flash Parameter table.
+    fn parse(table: &[u8; BASIC_DWORDS * 4]) -> Self {
+        let dword = |n: usize| {
+            let b = &table[(n - 1) * 4..n * 4];
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+        };
+
+        let first = dword(1);
+        let address_bytes = match first >> 17 & 0b11 {
+            0b00 => AddressBytes::Three,
+            0b01 => AddressBytes::ThreeOrFour,
+            _ => AddressBytes::Four,
+        };
+        let fast_reads = FastReads {
+            dual_output: first & 1 << 16 != 0,
+            dual_io: first & 1 << 20 != 0,
+            quad_io: first & 1 << 21 != 0,
+            quad_output: first & 1 << 22 != 0,
+        };
+
+        // Below 2 Gbit, the density is the size in bits minus one.
+        // Otherwise it is the power of two of the size in bits.
+        let density = dword(2);
+        let bits = if density & 1 << 31 == 0 {
+            u64::from(density) + 1
+        } else {
+            1 << (density & 0x7FFF_FFFF).min(63)
+        };
+
+        let mut erase_types = [None; 4];
+        for (i, erase_type) in erase_types.iter_mut().enumerate() {
+            let b = &table[7 * 4 + i * 2..];
+            // A size of 0 marks an unused entry.
+            if b[0] != 0 && b[0] < 32 {
+                *erase_type = Some(EraseType {
+                    size: 1 << b[0],
+                    opcode: b[1],
+                });
+            }
+        }
+
+        Self {
+            capacity: bits / 8,
+            address_bytes,
+            erase_types,
+            fast_reads,
+        }
+    }
+
+    /// Returns whether the chip supports erasing 4 KiB sectors, which the
+    /// 25-series driver relies on.
+    pub fn has_4k_erase(&self) -> bool {
+        self.erase_types.iter().any(|erase_type| match erase_type {
+            Some(erase_type) => erase_type.size == 4096,
+            None => false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use crate::series25::Capabilities;
+
+    /// SFDP data of a 16 Mbit chip with 4/32/64 KiB erases and dual and quad
+    /// reads.
+    fn sfdp() -> Vec<u8> {
+        let mut data = vec![0xFF; 0x80];
+        data[..16].copy_from_slice(&[
+            b'S', b'F', b'D', b'P', 0x05, 0x01, 0x00, 0xFF, // header, 1 parameter
+            0x00, 0x05, 0x01, 0x09, 0x80, 0x00, 0x00, 0xFF, // BFPT at 0x80
+        ]);
+        data.extend_from_slice(&[
+            0xE5, 0x20, 0xF1, 0xFF, // 4K erase 0x20, 3-byte, 1-1-2 1-2-2 1-4-4 1-1-4
+            0xFF, 0xFF, 0xFF, 0x00, // 16 Mbit
+            0x44, 0xEB, 0x08, 0x6B, // 1-4-4 and 1-1-4 settings
+            0x08, 0x3B, 0x42, 0xBB, // 1-1-2 and 1-2-2 settings
+            0xFE, 0xFF, 0xFF, 0xFF, // no 2-2-2 or 4-4-4
+            0xFF, 0xFF, 0x00, 0x00, // 2-2-2 settings
+            0xFF, 0xFF, 0x00, 0x00, // 4-4-4 settings
+            0x0C, 0x20, 0x0F, 0x52, // 4 KiB 0x20, 32 KiB 0x52
+            0x10, 0xD8, 0x00, 0x00, // 64 KiB 0xD8, unused
+        ]);
+        data
+    }
+
+    #[test]
+    fn test_read_params() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        assert_eq!(SfdpParams::read(&mut flash).unwrap(), None);
+
+        chip.borrow_mut().sfdp = sfdp();
+        let params = SfdpParams::read(&mut flash).unwrap().unwrap();
+        assert_eq!(params.capacity, 2 * 1024 * 1024);
+        assert_eq!(params.address_bytes, AddressBytes::Three);
+        assert!(params.has_4k_erase());
+        assert_eq!(
+            params.erase_types,
+            [
+                Some(EraseType {
+                    size: 4096,
+                    opcode: 0x20
+                }),
+                Some(EraseType {
+                    size: 32768,
+                    opcode: 0x52
+                }),
+                Some(EraseType {
+                    size: 65536,
+                    opcode: 0xD8
+                }),
+                None,
+            ]
+        );
+        assert_eq!(
+            params.fast_reads,
+            FastReads {
+                dual_output: true,
+                dual_io: true,
+                quad_output: true,
+                quad_io: true,
+            }
+        );
+
+        chip.borrow_mut().jedec_id = [0x12, 0x34, 0x15];
+        assert_eq!(
+            flash.capabilities().unwrap(),
+            Capabilities::SFDP | Capabilities::DUAL | Capabilities::QUAD
+        );
+
+        assert_eq!(flash.configure_from_sfdp().unwrap(), Some(params));
+        assert_eq!(flash.capacity(), Some(2 * 1024 * 1024));
+    }
+}
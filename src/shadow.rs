@@ -0,0 +1,250 @@
+//! Power-loss safe updates of a sector, using a spare sector as a shadow copy.
+//!
+//! [`Shadow::update`] writes the new contents to the spare sector first, and
+//! only then erases and rewrites the target sector. Each copy ends with a
+//! generation counter and a CRC-32, so [`Shadow::load`] can always find the
+//! newest complete copy, no matter where a power cut interrupted an update.
+//!
+//! # Format
+//!
+//! The payload starts at the beginning of each sector of
+//! [`Flash::sector_size`]. The last 8 bytes of the sector hold the generation as a little-endian `u32`, followed by the CRC-32
+//! of the payload and generation, also little-endian.
+
+use crate::delay::Delay;
+use crate::digest::{Crc32, Digest};
+use crate::geometry::SECTOR_SIZE;
+use crate::series25::Flash;
+use crate::utils::Context;
+use crate::{BlockDevice, Error, Read};
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+
+/// Size of the generation and CRC at the end of each sector.
+const TRAILER: u32 = 8;
+
+/// A sector protected by a shadow copy in a spare sector.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Shadow {
+    target: u32,
+    spare: u32,
+}
+
+impl Shadow {
+    /// Creates a handle for the sector at `target`, shadowed by the sector at
+    /// `spare`.
+    ///
+    /// On chips with sectors larger than 4 KiB, [`Shadow::load`] and
+    /// [`Shadow::update`] fail with [`Error::NotAligned`] unless both
+    /// addresses are aligned to [`Flash::sector_size`], so that erasing one
+    /// sector never touches the other.
+    ///
+    /// # Panics
+    ///
+    /// Panics if either address is not on a 4 KiB sector boundary.
+    pub fn new(target: u32, spare: u32) -> Self {
+        assert!(
+            (target | spare) & (SECTOR_SIZE - 1) == 0,
+            "shadow sectors must be sector-aligned"
+        );
+        Self { target, spare }
+    }
+
+    /// Reads the newest complete copy into `buf`, returning its generation.
+    ///
+    /// Returns `None` if neither sector holds a valid copy, eg. because
+    /// nothing was ever written. `buf` must have the same length for every
+    /// call to `load` and [`Shadow::update`], and the sector size must stay
+    /// the same.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` does not fit into a sector next to the 8-byte trailer.
+    pub fn load<SPI, CS, D>(
+        &self,
+        flash: &mut Flash<'_, SPI, CS, D>,
+        buf: &mut [u8],
+    ) -> Result<Option<u32>, Error<SPI, CS>>
+    where
+        SPI: Transfer<u8>,
+        CS: OutputPin,
+        D: Delay,
+    {
+        let sector_size = self.sector_size(flash)?;
+        assert!(
+            buf.len() as u32 <= sector_size - TRAILER,
+            "payload too large"
+        );
+
+        let target = self.check(flash, self.target, buf)?;
+        let spare = self.check(flash, self.spare, buf)?;
+        let (addr, generation) = match (target, spare) {
+            (Some(t), Some(s)) if s > t => (self.spare, s),
+            (Some(t), _) => (self.target, t),
+            (None, Some(s)) => (self.spare, s),
+            (None, None) => return Ok(None),
+        };
+        flash.read(addr, buf).context("shadow load", addr)?;
+        Ok(Some(generation))
+    }
+
+    /// Updates the protected sector with the contents produced by `f`.
+    ///
+    /// `f` is called with `buf` holding the current contents, or all `0xFF`
+    /// if there is no valid copy yet, and modifies it in place.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` does not fit into a sector next to the 8-byte trailer.
+    pub fn update<SPI, CS, D, F>(
+        &self,
+        flash: &mut Flash<'_, SPI, CS, D>,
+        buf: &mut [u8],
+        f: F,
+    ) -> Result<(), Error<SPI, CS>>
+    where
+        SPI: Transfer<u8>,
+        CS: OutputPin,
+        D: Delay,
+        F: FnOnce(&mut [u8]),
+    {
+        let sector_size = self.sector_size(flash)?;
+        let generation = match self.load(flash, buf)? {
+            Some(generation) => generation.wrapping_add(1),
+            None => {
+                buf.iter_mut().for_each(|byte| *byte = 0xFF);
+                0
+            }
+        };
+        f(buf);
+
+        let mut trailer = [0; TRAILER as usize];
+        trailer[..4].copy_from_slice(&generation.to_le_bytes());
+        let crc = crc(buf, generation);
+        trailer[4..].copy_from_slice(&crc.to_le_bytes());
+
+        for &addr in &[self.spare, self.target] {
+            flash.erase_sectors(addr, 1).context("shadow erase", addr)?;
+            flash.write_bytes(addr, buf).context("shadow write", addr)?;
+            // The trailer goes last, so it only exists once the payload does.
+            flash
+                .write_bytes(addr + sector_size - TRAILER, &mut trailer)
+                .context("shadow write", addr)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the generation of the copy at `addr` if it is valid, reading
+    /// the payload through `buf`.
+    fn check<SPI, CS, D>(
+        &self,
+        flash: &mut Flash<'_, SPI, CS, D>,
+        addr: u32,
+        buf: &mut [u8],
+    ) -> Result<Option<u32>, Error<SPI, CS>>
+    where
+        SPI: Transfer<u8>,
+        CS: OutputPin,
+        D: Delay,
+    {
+        let mut trailer = [0; TRAILER as usize];
+        flash
+            .read(addr + flash.sector_size() - TRAILER, &mut trailer)
+            .context("shadow check", addr)?;
+        let generation = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        let expected = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+
+        flash.read(addr, buf).context("shadow check", addr)?;
+        if crc(buf, generation) == expected {
+            Ok(Some(generation))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns the sector size of `flash`, after checking that both sectors
+    /// are aligned to it.
+    fn sector_size<SPI, CS, D>(&self, flash: &Flash<'_, SPI, CS, D>) -> Result<u32, Error<SPI, CS>>
+    where
+        SPI: Transfer<u8>,
+        CS: OutputPin,
+        D: Delay,
+    {
+        let sector_size = flash.sector_size();
+        if (self.target | self.spare) & (sector_size - 1) == 0 {
+            Ok(sector_size)
+        } else {
+            Err(Error::NotAligned)
+        }
+    }
+}
+
+/// The CRC-32 stored in the trailer.
+fn crc(payload: &[u8], generation: u32) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(payload);
+    crc.update(&generation.to_le_bytes());
+    crc.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Geometry, BLOCK64_SIZE};
+    use crate::mock;
+
+    #[test]
+    fn test_update() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        let shadow = Shadow::new(0x1000, 0x2000);
+
+        let mut buf = [0; 16];
+        assert_eq!(shadow.load(&mut flash, &mut buf).unwrap(), None);
+        shadow
+            .update(&mut flash, &mut buf, |buf| buf[0] = 1)
+            .unwrap();
+        shadow
+            .update(&mut flash, &mut buf, |buf| buf[1] = 2)
+            .unwrap();
+        assert_eq!(shadow.load(&mut flash, &mut buf).unwrap(), Some(1));
+        assert_eq!(buf[..3], [1, 2, 0xFF]);
+
+        // Power cut after the target was erased: the spare copy survives.
+        chip.borrow_mut().memory[0x1000..0x2000]
+            .iter_mut()
+            .for_each(|b| *b = 0xFF);
+        buf = [0; 16];
+        assert_eq!(shadow.load(&mut flash, &mut buf).unwrap(), Some(1));
+        assert_eq!(buf[..3], [1, 2, 0xFF]);
+    }
+
+    #[test]
+    fn test_large_sectors() {
+        let (chip, spi, cs) = mock::chip(0x20000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        flash.set_geometry(Geometry {
+            sector_size: BLOCK64_SIZE,
+            ..Geometry::standard(0x20000)
+        });
+
+        // Both copies would share one 64 KiB sector.
+        let mut buf = [0; 16];
+        match Shadow::new(0x1000, 0x2000).update(&mut flash, &mut buf, |_| {}) {
+            Err(Error::NotAligned) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(chip.borrow().transactions.iter().all(|t| t[0] != 0xD8));
+
+        let shadow = Shadow::new(0, 0x10000);
+        shadow
+            .update(&mut flash, &mut buf, |buf| buf[0] = 1)
+            .unwrap();
+        assert_eq!(chip.borrow().memory[0xFFF8..0xFFFC], [0, 0, 0, 0]);
+        chip.borrow_mut().memory[..0x10000]
+            .iter_mut()
+            .for_each(|b| *b = 0xFF);
+        assert_eq!(shadow.load(&mut flash, &mut buf).unwrap(), Some(0));
+        assert_eq!(buf[0], 1);
+    }
+}
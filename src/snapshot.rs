@@ -0,0 +1,245 @@
+//! Saving and restoring the complete state of a 25-series chip.
+//!
+//! A snapshot contains the JEDEC ID, the status registers and the full array
+//! contents of a chip, so that it can be cloned onto another chip of the same
+//! type, protection bits included.
+//!
+//! This module is only available with the `std` Cargo feature.
+//!
+//! # Format
+//!
+//! | Offset | Size       | Contents                                  |
+//! |--------|------------|-------------------------------------------|
+//! | 0      | 4          | Magic bytes `SPIM`                        |
+//! | 4      | 1          | Format version, currently 2               |
+//! | 5      | 3          | Manufacturer code and device ID           |
+//! | 8      | 1          | Number of status registers, 1 or 3        |
+//! | 9      | 3          | Status registers 1 to 3, unused ones 0    |
+//! | 12     | 4          | Capacity in bytes, little-endian          |
+//! | 16     | *capacity* | Array contents                            |
+//!
+//! The second and third status registers are only saved for Winbond chips.
+//! Version 1 snapshots, which only hold the known bits of the first status
+//! register at offset 8, aren't supported anymore.
+
+use crate::delay::Delay;
+use crate::series25::{Flash, Status, Status2, Status3};
+use crate::utils::Context;
+use crate::{BlockDevice, Error, Read};
+use core::fmt::{self, Debug, Display};
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+use std::io;
+
+const MAGIC: [u8; 4] = *b"SPIM";
+const VERSION: u8 = 2;
+const HEADER: usize = 16;
+const CHUNK: usize = 256;
+/// Manufacturer code of Winbond, whose chips have 3 status registers.
+const WINBOND: u8 = 0xEF;
+
+/// An error that occurred while taking or restoring a snapshot.
+pub enum SnapshotError<SPI: Transfer<u8>, CS: OutputPin> {
+    /// Accessing the chip failed.
+    Flash(Error<SPI, CS>),
+
+    /// Reading or writing the snapshot failed.
+    Io(io::Error),
+
+    /// The snapshot is malformed, or was taken from a different chip.
+    Format,
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> From<Error<SPI, CS>> for SnapshotError<SPI, CS> {
+    fn from(e: Error<SPI, CS>) -> Self {
+        SnapshotError::Flash(e)
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> From<io::Error> for SnapshotError<SPI, CS> {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> Debug for SnapshotError<SPI, CS>
+where
+    SPI::Error: Debug,
+    CS::Error: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Flash(e) => write!(f, "SnapshotError::Flash({:?})", e),
+            SnapshotError::Io(e) => write!(f, "SnapshotError::Io({:?})", e),
+            SnapshotError::Format => f.write_str("SnapshotError::Format"),
+        }
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> Display for SnapshotError<SPI, CS>
+where
+    SPI::Error: Display,
+    CS::Error: Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Flash(e) => Display::fmt(e, f),
+            SnapshotError::Io(e) => write!(f, "I/O error: {}", e),
+            SnapshotError::Format => f.write_str("invalid snapshot or wrong chip"),
+        }
+    }
+}
+
+/// Writes a snapshot of the first `capacity` bytes of the chip and its
+/// registers to `out`.
+pub fn snapshot<SPI, CS, D, W>(
+    flash: &mut Flash<'_, SPI, CS, D>,
+    capacity: u32,
+    mut out: W,
+) -> Result<(), SnapshotError<SPI, CS>>
+where
+    SPI: Transfer<u8>,
+    CS: OutputPin,
+    D: Delay,
+    W: io::Write,
+{
+    let id = flash.read_jedec_id()?;
+    let registers = if id.mfr_code() == WINBOND { 3 } else { 1 };
+    let mut status = [0; 3];
+    for (i, byte) in status.iter_mut().enumerate().take(registers) {
+        *byte = flash.read_status_register(i as u8 + 1)?;
+    }
+
+    out.write_all(&MAGIC)?;
+    out.write_all(&[VERSION, id.mfr_code()])?;
+    out.write_all(&id.device_id()[..2])?;
+    out.write_all(&[registers as u8])?;
+    out.write_all(&status)?;
+    out.write_all(&capacity.to_le_bytes())?;
+
+    let mut buf = [0; CHUNK];
+    let mut addr = 0;
+    while addr < capacity {
+        let len = CHUNK.min((capacity - addr) as usize);
+        flash
+            .read(addr, &mut buf[..len])
+            .context("snapshot read", addr)?;
+        out.write_all(&buf[..len])?;
+        addr += len as u32;
+    }
+
+    Ok(())
+}
+
+/// Restores a snapshot read from `input` onto the chip.
+///
+/// The snapshot must have been taken from a chip with the same JEDEC ID. The
+/// chip is erased completely, then programmed with the saved contents (skipping
+/// erased pages), and finally the status registers are restored. Of the
+/// second and third register, only the bits that can be changed back (`QE`,
+/// `CMP`, `WPS` and `DRV`) are restored, so that one-time programmable lock
+/// bits are never set by accident. The chip must not be write-protected when
+/// this is called.
+pub fn restore<SPI, CS, D, R>(
+    flash: &mut Flash<'_, SPI, CS, D>,
+    mut input: R,
+) -> Result<(), SnapshotError<SPI, CS>>
+where
+    SPI: Transfer<u8>,
+    CS: OutputPin,
+    D: Delay,
+    R: io::Read,
+{
+    let mut header = [0; HEADER];
+    input.read_exact(&mut header)?;
+    let id = flash.read_jedec_id()?;
+    let registers = header[8];
+    if header[..4] != MAGIC
+        || header[4] != VERSION
+        || header[5] != id.mfr_code()
+        || header[6..8] != id.device_id()[..2]
+        || (registers != 1 && registers != 3)
+    {
+        return Err(SnapshotError::Format);
+    }
+    let status = [header[9], header[10], header[11]];
+    let capacity = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+
+    flash.erase_all().context("snapshot erase", 0)?;
+
+    let mut buf = [0; CHUNK];
+    let mut addr = 0;
+    while addr < capacity {
+        let len = CHUNK.min((capacity - addr) as usize);
+        input.read_exact(&mut buf[..len])?;
+        if buf[..len].iter().any(|&byte| byte != 0xFF) {
+            flash
+                .write_bytes(addr, &mut buf[..len])
+                .context("snapshot restore", addr)?;
+        }
+        addr += len as u32;
+    }
+
+    if registers == 3 {
+        let writable = [
+            0,
+            (Status2::QE | Status2::CMP).bits(),
+            (Status3::WPS | Status3::DRV).bits(),
+        ];
+        for register in 2..=3 {
+            let mask = writable[usize::from(register) - 1];
+            let current = flash.read_status_register(register)?;
+            let value = current & !mask | status[usize::from(register) - 1] & mask;
+            if value != current {
+                flash.write_status_register(register, value)?;
+            }
+        }
+    }
+    // The first register goes last, since SRWD may lock the others.
+    let value = status[0] & !(Status::BUSY | Status::WEL).bits();
+    if value != flash.read_status_register(1)? {
+        flash.write_status_register(1, value)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn test_clone_chip() {
+        let (source, spi, cs) = mock::chip(0x2000);
+        source.borrow_mut().memory[0x1234] = 0x42;
+        // TB, BP0, and QE, CMP and an OTP lock bit, and DRV=1.
+        source.borrow_mut().status = 0b0100_0100;
+        source.borrow_mut().status2 = 0b0100_1010;
+        source.borrow_mut().status3 = 0b0010_0000;
+        let mut flash = Flash::init(spi, cs).unwrap();
+        let mut image = Vec::new();
+        snapshot(&mut flash, 0x2000, &mut image).unwrap();
+        assert_eq!(image.len(), 16 + 0x2000);
+
+        let (target, spi, cs) = mock::chip(0x2000);
+        target.borrow_mut().memory[0] = 0x00;
+        let mut flash = Flash::init(spi, cs).unwrap();
+        restore(&mut flash, &image[..]).unwrap();
+        {
+            let (target, source) = (target.borrow(), source.borrow());
+            assert_eq!(target.memory, source.memory);
+            assert_eq!(target.status, 0b0100_0100);
+            assert_eq!(target.status2, 0b0100_0010);
+            assert_eq!(target.status3, 0b0010_0000);
+        }
+
+        let (other, spi, cs) = mock::chip(0x2000);
+        other.borrow_mut().jedec_id = [0xC2, 0x20, 0x14];
+        let mut flash = Flash::init(spi, cs).unwrap();
+        match restore(&mut flash, &image[..]) {
+            Err(SnapshotError::Format) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
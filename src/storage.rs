@@ -0,0 +1,306 @@
+//! Implementations of the `embedded-storage` NOR flash traits.
+//!
+//! This lets the 25-series driver be used directly with crates built on
+//! `embedded-storage`, like bootloaders and `sequential-storage`. It is only
+//! available with the `embedded-storage` Cargo feature.
+//!
+//! The traits are implemented on [`Flash`] for chips erased in 4 KiB sectors.
+//! Chips with larger sectors (see [`Flash::set_geometry`]) need a
+//! [`SectorFlash`], which carries the sector size as its `ERASE_SIZE`.
+
+use crate::delay::{Delay, NoDelay};
+use crate::geometry::{PAGE_SIZE, SECTOR_SIZE};
+use crate::series25::Flash;
+use crate::{BlockDevice, Error, Read};
+use core::fmt::Debug;
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, MultiwriteNorFlash, NorFlash, NorFlashError,
+    NorFlashErrorKind, ReadNorFlash,
+};
+
+impl<SPI: Transfer<u8>, CS: OutputPin> NorFlashError for Error<SPI, CS>
+where
+    SPI::Error: Debug,
+    CS::Error: Debug,
+{
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Error::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            Error::NotAligned => NorFlashErrorKind::NotAligned,
+            _ => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+/// Converts the error of one of the `check_*` functions.
+fn checked<SPI: Transfer<u8>, CS: OutputPin>(
+    result: Result<(), NorFlashErrorKind>,
+) -> Result<(), Error<SPI, CS>> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(NorFlashErrorKind::NotAligned) => Err(Error::NotAligned),
+        Err(_) => Err(Error::OutOfBounds),
+    }
+}
+
+// The trait methods of `Flash` and `SectorFlash` share these, after
+// checking their arguments.
+
+fn capacity<SPI: Transfer<u8>, CS: OutputPin, D: Delay>(flash: &Flash<'_, SPI, CS, D>) -> usize {
+    flash.capacity().unwrap_or(0) as usize
+}
+
+fn erase<SPI: Transfer<u8>, CS: OutputPin, D: Delay>(
+    flash: &mut Flash<'_, SPI, CS, D>,
+    from: u32,
+    to: u32,
+) -> Result<(), Error<SPI, CS>> {
+    if (from | to) & (flash.sector_size() - 1) != 0 {
+        return Err(Error::NotAligned);
+    }
+    flash.erase_range(from, to - from)
+}
+
+fn write<SPI: Transfer<u8>, CS: OutputPin, D: Delay>(
+    flash: &mut Flash<'_, SPI, CS, D>,
+    offset: u32,
+    bytes: &[u8],
+) -> Result<(), Error<SPI, CS>> {
+    // `write_bytes` needs a mutable buffer, so go through a page-sized
+    // copy, ending every chunk at a page boundary.
+    let mut buf = [0; PAGE_SIZE as usize];
+    let mut done = 0;
+    while done < bytes.len() {
+        let addr = offset + done as u32;
+        let len = ((PAGE_SIZE - addr % PAGE_SIZE) as usize).min(bytes.len() - done);
+        buf[..len].copy_from_slice(&bytes[done..done + len]);
+        flash.write_bytes(addr, &mut buf[..len])?;
+        done += len;
+    }
+    Ok(())
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin, D: Delay> ErrorType for Flash<'_, SPI, CS, D>
+where
+    SPI::Error: Debug,
+    CS::Error: Debug,
+{
+    type Error = Error<SPI, CS>;
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin, D: Delay> ReadNorFlash for Flash<'_, SPI, CS, D>
+where
+    SPI::Error: Debug,
+    CS::Error: Debug,
+{
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        checked(check_read(self, offset, bytes.len()))?;
+        Read::read(self, offset, bytes)
+    }
+
+    /// Returns the capacity set via [`Flash::set_capacity`], or 0 if it is
+    /// unknown, in which case every access fails with
+    /// [`Error::OutOfBounds`].
+    fn capacity(&self) -> usize {
+        capacity(self)
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin, D: Delay> NorFlash for Flash<'_, SPI, CS, D>
+where
+    SPI::Error: Debug,
+    CS::Error: Debug,
+{
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = SECTOR_SIZE as usize;
+
+    /// Fails with [`Error::NotAligned`] if the driver was set up for sectors
+    /// larger than `ERASE_SIZE`. Use a [`SectorFlash`] for those chips.
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        checked(check_erase(self, from, to))?;
+        erase(self, from, to)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        checked(check_write(self, offset, bytes.len()))?;
+        write(self, offset, bytes)
+    }
+}
+
+/// Programming can clear bits that are still set without an erase in
+/// between.
+impl<SPI: Transfer<u8>, CS: OutputPin, D: Delay> MultiwriteNorFlash for Flash<'_, SPI, CS, D>
+where
+    SPI::Error: Debug,
+    CS::Error: Debug,
+{
+}
+
+/// A 25-series driver for chips whose sectors are `ERASE_SIZE` bytes, for use
+/// with the `embedded-storage` traits.
+///
+/// Unlike [`Flash`] itself, whose `ERASE_SIZE` is always 4 KiB, this reports
+/// the sector size the driver was configured with, eg. 64 KiB for chips
+/// without a 4 KiB erase command.
+#[derive(Debug)]
+pub struct SectorFlash<'a, SPI: Transfer<u8>, CS: OutputPin, const ERASE_SIZE: usize, D = NoDelay> {
+    flash: Flash<'a, SPI, CS, D>,
+}
+
+impl<'a, SPI: Transfer<u8>, CS: OutputPin, const ERASE_SIZE: usize, D: Delay>
+    SectorFlash<'a, SPI, CS, ERASE_SIZE, D>
+{
+    /// Wraps `flash`, which has to be configured for sectors of
+    /// `ERASE_SIZE` bytes already (see [`Flash::set_geometry`]).
+    ///
+    /// Gives `flash` back if its sector size is different.
+    #[allow(clippy::result_large_err)] // there's no heap to box the driver in
+    pub fn new(flash: Flash<'a, SPI, CS, D>) -> Result<Self, Flash<'a, SPI, CS, D>> {
+        if flash.sector_size() as usize == ERASE_SIZE {
+            Ok(Self { flash })
+        } else {
+            Err(flash)
+        }
+    }
+
+    /// Returns a reference to the wrapped driver.
+    pub fn get_ref(&self) -> &Flash<'a, SPI, CS, D> {
+        &self.flash
+    }
+
+    /// Returns a mutable reference to the wrapped driver.
+    ///
+    /// Changing its geometry makes all erases fail with
+    /// [`Error::NotAligned`].
+    pub fn get_mut(&mut self) -> &mut Flash<'a, SPI, CS, D> {
+        &mut self.flash
+    }
+
+    /// Releases the wrapped driver.
+    pub fn release(self) -> Flash<'a, SPI, CS, D> {
+        self.flash
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin, const ERASE_SIZE: usize, D: Delay> ErrorType
+    for SectorFlash<'_, SPI, CS, ERASE_SIZE, D>
+where
+    SPI::Error: Debug,
+    CS::Error: Debug,
+{
+    type Error = Error<SPI, CS>;
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin, const ERASE_SIZE: usize, D: Delay> ReadNorFlash
+    for SectorFlash<'_, SPI, CS, ERASE_SIZE, D>
+where
+    SPI::Error: Debug,
+    CS::Error: Debug,
+{
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        checked(check_read(self, offset, bytes.len()))?;
+        Read::read(&mut self.flash, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        capacity(&self.flash)
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin, const ERASE_SIZE: usize, D: Delay> NorFlash
+    for SectorFlash<'_, SPI, CS, ERASE_SIZE, D>
+where
+    SPI::Error: Debug,
+    CS::Error: Debug,
+{
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        checked(check_erase(self, from, to))?;
+        erase(&mut self.flash, from, to)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        checked(check_write(self, offset, bytes.len()))?;
+        write(&mut self.flash, offset, bytes)
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin, const ERASE_SIZE: usize, D: Delay> MultiwriteNorFlash
+    for SectorFlash<'_, SPI, CS, ERASE_SIZE, D>
+where
+    SPI::Error: Debug,
+    CS::Error: Debug,
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Geometry, BLOCK64_SIZE};
+    use crate::mock;
+
+    #[test]
+    fn test_nor_flash() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        assert_eq!(ReadNorFlash::capacity(&flash), 0);
+        flash.set_capacity(0x10000);
+        assert_eq!(ReadNorFlash::capacity(&flash), 0x10000);
+
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        NorFlash::write(&mut flash, 0x1F0, &data).unwrap();
+        let mut buf = [0; 300];
+        ReadNorFlash::read(&mut flash, 0x1F0, &mut buf).unwrap();
+        assert_eq!(buf[..], data[..]);
+
+        match NorFlash::erase(&mut flash, 0x100, 0x1000) {
+            Err(e) => assert_eq!(NorFlashError::kind(&e), NorFlashErrorKind::NotAligned),
+            Ok(()) => panic!("unaligned erase succeeded"),
+        }
+        match NorFlash::write(&mut flash, 0xFFFF, &[0, 0]) {
+            Err(e) => assert_eq!(NorFlashError::kind(&e), NorFlashErrorKind::OutOfBounds),
+            Ok(()) => panic!("out of bounds write succeeded"),
+        }
+        NorFlash::erase(&mut flash, 0, 0x1000).unwrap();
+        assert!(chip.borrow().memory[..0x1000].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn test_sector_flash() {
+        let (chip, spi, cs) = mock::chip(0x20000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        flash.set_geometry(Geometry {
+            sector_size: BLOCK64_SIZE,
+            ..Geometry::standard(0x20000)
+        });
+        let flash = match SectorFlash::<_, _, 0x1000>::new(flash) {
+            Ok(_) => panic!("sector size mismatch accepted"),
+            Err(flash) => flash,
+        };
+        let mut flash = SectorFlash::<_, _, 0x10000>::new(flash).unwrap();
+        assert_eq!(
+            <SectorFlash<'_, mock::Spi, mock::Cs, 0x10000> as NorFlash>::ERASE_SIZE,
+            0x10000
+        );
+
+        chip.borrow_mut().memory[0x10000] = 0;
+        match NorFlash::erase(&mut flash, 0, 0x1000) {
+            Err(e) => assert_eq!(NorFlashError::kind(&e), NorFlashErrorKind::NotAligned),
+            Ok(()) => panic!("unaligned erase succeeded"),
+        }
+        NorFlash::erase(&mut flash, 0x10000, 0x20000).unwrap();
+        assert_eq!(chip.borrow().memory[0x10000], 0xFF);
+        NorFlash::write(&mut flash, 0x10000, &[1, 2]).unwrap();
+        let mut buf = [0; 2];
+        ReadNorFlash::read(&mut flash, 0x10000, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2]);
+    }
+}
@@ -0,0 +1,312 @@
+//! Tag-length-value configuration records in a dedicated region of a chip.
+//!
+//! Records are appended one after another, so updating a value only costs a
+//! write, and the most recently written record for a tag wins. Once the region
+//! is full, it has to be [cleared] and the current values written again.
+//!
+//! [cleared]: Tlv::clear
+//!
+//! # Format
+//!
+//! | Size  | Contents                                           |
+//! |-------|----------------------------------------------------|
+//! | 1     | Tag, `0x00` to `0xFE` (`0xFF` marks erased memory) |
+//! | 1     | Length of the value                                |
+//! | *len* | Value                                              |
+//! | 2     | [`Crc16`] of the above, little-endian              |
+//!
+//! Records with a bad checksum, eg. from an interrupted write, are skipped.
+
+use crate::delay::Delay;
+use crate::digest::{Crc16, Digest};
+use crate::series25::Flash;
+use crate::utils::Context;
+use crate::{BlockDevice, Error, Read};
+use core::fmt::{self, Debug, Display};
+use core::ops::Range;
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+
+/// Tag of erased memory, marking the end of the records.
+const END: u8 = 0xFF;
+/// Size of the tag and length fields.
+const HEADER: u32 = 2;
+/// Size of the checksum.
+const CRC: u32 = 2;
+
+/// An error that occurred while accessing TLV records.
+pub enum TlvError<SPI: Transfer<u8>, CS: OutputPin> {
+    /// Accessing the chip failed.
+    Flash(Error<SPI, CS>),
+
+    /// There is no room left in the region for the record.
+    Full,
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> From<Error<SPI, CS>> for TlvError<SPI, CS> {
+    fn from(e: Error<SPI, CS>) -> Self {
+        TlvError::Flash(e)
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> Debug for TlvError<SPI, CS>
+where
+    SPI::Error: Debug,
+    CS::Error: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlvError::Flash(e) => write!(f, "TlvError::Flash({:?})", e),
+            TlvError::Full => f.write_str("TlvError::Full"),
+        }
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> Display for TlvError<SPI, CS>
+where
+    SPI::Error: Display,
+    CS::Error: Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlvError::Flash(e) => Display::fmt(e, f),
+            TlvError::Full => f.write_str("no room left for TLV record"),
+        }
+    }
+}
+
+/// A region of a chip holding TLV records.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tlv {
+    range: Range<u32>,
+}
+
+impl Tlv {
+    /// Creates a handle to the records stored in `range`.
+    ///
+    /// The region should start and end on sector boundaries (see
+    /// [`Flash::sector_size`]), since [`Tlv::clear`] erases whole sectors.
+    pub fn new(range: Range<u32>) -> Self {
+        Self { range }
+    }
+
+    /// Calls `f` with the tag and value of every valid record, oldest first.
+    pub fn for_each<SPI, CS, D, F>(
+        &self,
+        flash: &mut Flash<'_, SPI, CS, D>,
+        f: F,
+    ) -> Result<(), TlvError<SPI, CS>>
+    where
+        SPI: Transfer<u8>,
+        CS: OutputPin,
+        D: Delay,
+        F: FnMut(u8, &[u8]),
+    {
+        self.scan(flash, f)?;
+        Ok(())
+    }
+
+    /// Looks up the latest value stored for `tag` and copies it into `buf`.
+    ///
+    /// Returns the length of the value, or `None` if there is no record for
+    /// `tag`. If `buf` is too short, only the start of the value is copied.
+    pub fn find<SPI, CS, D>(
+        &self,
+        flash: &mut Flash<'_, SPI, CS, D>,
+        tag: u8,
+        buf: &mut [u8],
+    ) -> Result<Option<usize>, TlvError<SPI, CS>>
+    where
+        SPI: Transfer<u8>,
+        CS: OutputPin,
+        D: Delay,
+    {
+        let mut found = None;
+        self.scan(flash, |t, value| {
+            if t == tag {
+                let n = value.len().min(buf.len());
+                buf[..n].copy_from_slice(&value[..n]);
+                found = Some(value.len());
+            }
+        })?;
+        Ok(found)
+    }
+
+    /// Appends a record storing `value` for `tag`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tag` is `0xFF` or `value` is longer than 255 bytes.
+    pub fn append<SPI, CS, D>(
+        &self,
+        flash: &mut Flash<'_, SPI, CS, D>,
+        tag: u8,
+        value: &[u8],
+    ) -> Result<(), TlvError<SPI, CS>>
+    where
+        SPI: Transfer<u8>,
+        CS: OutputPin,
+        D: Delay,
+    {
+        assert!(tag != END, "tag 0xFF is reserved");
+        assert!(value.len() <= 255, "TLV value too long");
+
+        let end = self.scan(flash, |_, _| {})?;
+        let len = HEADER as usize + value.len() + CRC as usize;
+        if end + len as u32 > self.range.end {
+            return Err(TlvError::Full);
+        }
+
+        let mut record = [0; HEADER as usize + 255 + CRC as usize];
+        record[0] = tag;
+        record[1] = value.len() as u8;
+        record[2..2 + value.len()].copy_from_slice(value);
+        let mut crc = Crc16::new();
+        crc.update(&record[..len - 2]);
+        record[len - 2..len].copy_from_slice(&crc.finish().to_le_bytes());
+        flash
+            .write_bytes(end, &mut record[..len])
+            .context("TLV append", end)?;
+        Ok(())
+    }
+
+    /// Erases all records.
+    ///
+    /// Fails with [`Error::NotAligned`], without erasing anything, if the
+    /// region doesn't start and end on sector boundaries.
+    pub fn clear<SPI, CS, D>(
+        &self,
+        flash: &mut Flash<'_, SPI, CS, D>,
+    ) -> Result<(), TlvError<SPI, CS>>
+    where
+        SPI: Transfer<u8>,
+        CS: OutputPin,
+        D: Delay,
+    {
+        let sector_size = flash.sector_size();
+        if (self.range.start | self.range.end) & (sector_size - 1) != 0 {
+            return Err(TlvError::Flash(Error::NotAligned));
+        }
+        let mut addr = self.range.start;
+        while addr < self.range.end {
+            flash.erase_sectors(addr, 1).context("TLV clear", addr)?;
+            addr += sector_size;
+        }
+        Ok(())
+    }
+
+    /// Calls `f` for every valid record and returns the address after the
+    /// last one.
+    fn scan<SPI, CS, D, F>(
+        &self,
+        flash: &mut Flash<'_, SPI, CS, D>,
+        mut f: F,
+    ) -> Result<u32, Error<SPI, CS>>
+    where
+        SPI: Transfer<u8>,
+        CS: OutputPin,
+        D: Delay,
+        F: FnMut(u8, &[u8]),
+    {
+        let mut addr = self.range.start;
+        let mut record = [0; HEADER as usize + 255 + CRC as usize];
+        while addr + HEADER <= self.range.end {
+            flash
+                .read(addr, &mut record[..2])
+                .context("TLV scan", addr)?;
+            if record[0] == END {
+                return Ok(addr);
+            }
+
+            let len = HEADER as usize + usize::from(record[1]) + CRC as usize;
+            if addr + len as u32 > self.range.end {
+                // Garbage, or a record cut off by the end of the region.
+                break;
+            }
+            flash
+                .read(addr + HEADER, &mut record[2..len])
+                .context("TLV scan", addr)?;
+            let mut crc = Crc16::new();
+            crc.update(&record[..len - 2]);
+            if crc.finish() == u16::from_le_bytes([record[len - 2], record[len - 1]]) {
+                f(record[0], &record[2..len - 2]);
+            }
+            addr += len as u32;
+        }
+        Ok(self.range.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Geometry, BLOCK64_SIZE};
+    use crate::mock;
+
+    #[test]
+    fn test_latest_wins() {
+        let (chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        let tlv = Tlv::new(0x1000..0x2000);
+
+        let mut buf = [0; 8];
+        assert_eq!(tlv.find(&mut flash, 1, &mut buf).unwrap(), None);
+        tlv.append(&mut flash, 1, b"old").unwrap();
+        tlv.append(&mut flash, 2, b"other").unwrap();
+        tlv.append(&mut flash, 1, b"new!").unwrap();
+        assert_eq!(tlv.find(&mut flash, 1, &mut buf).unwrap(), Some(4));
+        assert_eq!(&buf[..4], b"new!");
+
+        // Corrupt the newest record, the previous value shows through again.
+        chip.borrow_mut().memory[0x1000 + 7 + 9 + 2] = 0;
+        assert_eq!(tlv.find(&mut flash, 1, &mut buf).unwrap(), Some(3));
+        assert_eq!(&buf[..3], b"old");
+
+        let mut tags = Vec::new();
+        tlv.for_each(&mut flash, |tag, _| tags.push(tag)).unwrap();
+        assert_eq!(tags, [1, 2]);
+
+        tlv.clear(&mut flash).unwrap();
+        assert_eq!(tlv.find(&mut flash, 1, &mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn test_full() {
+        let (_chip, spi, cs) = mock::chip(0x10000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        let tlv = Tlv::new(0..10);
+
+        tlv.append(&mut flash, 1, b"abcd").unwrap();
+        match tlv.append(&mut flash, 1, b"") {
+            Err(TlvError::Full) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match tlv.clear(&mut flash) {
+            Err(TlvError::Flash(Error::NotAligned)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_clear_large_sectors() {
+        let (chip, spi, cs) = mock::chip(0x20000);
+        let mut flash = Flash::init(spi, cs).unwrap();
+        flash.set_geometry(Geometry {
+            sector_size: BLOCK64_SIZE,
+            ..Geometry::standard(0x20000)
+        });
+        let tlv = Tlv::new(0x10000..0x20000);
+        tlv.append(&mut flash, 1, b"abcd").unwrap();
+
+        chip.borrow_mut().transactions.clear();
+        tlv.clear(&mut flash).unwrap();
+        let erases = chip
+            .borrow()
+            .transactions
+            .iter()
+            .filter(|t| t[0] == 0xD8)
+            .count();
+        assert_eq!(erases, 1);
+        assert_eq!(tlv.find(&mut flash, 1, &mut [0; 4]).unwrap(), None);
+    }
+}
@@ -1,4 +1,8 @@
+use crate::delay::Delay;
+use crate::Error;
 use core::fmt;
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
 
 pub struct HexSlice<T>(pub T)
 where
@@ -16,3 +20,187 @@ impl<T: AsRef<[u8]>> fmt::Debug for HexSlice<T> {
         f.write_str("]")
     }
 }
+
+/// Adds the operation and address to failures of nested layers.
+///
+/// The error itself is passed on unchanged, but a log line naming the
+/// operation, the address and the kind of error is emitted, so failures deep
+/// inside eg. the TLV store can still be traced back to their cause.
+pub trait Context: Sized {
+    fn context(self, op: &str, addr: u32) -> Self;
+}
+
+impl<T, SPI: Transfer<u8>, CS: OutputPin> Context for Result<T, Error<SPI, CS>> {
+    fn context(self, op: &str, addr: u32) -> Self {
+        if let Err(e) = &self {
+            error!("{} at {:#x} failed: {}", op, addr, e.kind());
+        }
+        self
+    }
+}
+
+/// Delays around the chip-select edges of a transaction, for chips or level
+/// shifters that need more setup or hold time than the SPI master leaves.
+pub struct CsDelay<'a> {
+    pub delay: &'a mut dyn Delay,
+    /// Time from selecting the chip to the first clock edge, in µs.
+    pub setup_us: u32,
+    /// Time from the last clock edge to deselecting the chip, in µs.
+    pub hold_us: u32,
+}
+
+/// Selects the chip, runs `transfer`, and deselects the chip again, even if
+/// `transfer` fails.
+fn transaction<SPI: Transfer<u8>, CS: OutputPin>(
+    spi: &mut SPI,
+    cs: &mut CS,
+    mut cs_delay: Option<CsDelay<'_>>,
+    transfer: impl FnOnce(&mut SPI) -> Result<(), SPI::Error>,
+) -> Result<(), Error<SPI, CS>> {
+    cs.set_low().map_err(Error::Gpio)?;
+    if let Some(d) = &mut cs_delay {
+        d.delay.delay_us(d.setup_us);
+    }
+    let spi_result = transfer(spi);
+    if let Some(d) = &mut cs_delay {
+        d.delay.delay_us(d.hold_us);
+    }
+    cs.set_high().map_err(Error::Gpio)?;
+    spi_result.map_err(Error::Spi)
+}
+
+/// Runs one SPI transaction made up of several phases.
+///
+/// The chip is selected for the whole transaction, and each phase is
+/// transferred in order, overwriting it with the bytes received at the same
+/// time. Empty phases are skipped. If a transfer fails, the remaining phases
+/// are skipped, but the chip is still deselected.
+pub fn spi_command<SPI: Transfer<u8>, CS: OutputPin>(
+    spi: &mut SPI,
+    cs: &mut CS,
+    cs_delay: Option<CsDelay<'_>>,
+    phases: &mut [&mut [u8]],
+) -> Result<(), Error<SPI, CS>> {
+    transaction(spi, cs, cs_delay, |spi| {
+        phases
+            .iter_mut()
+            .filter(|phase| !phase.is_empty())
+            .try_for_each(|phase| spi.transfer(phase).map(|_| ()))
+    })
+}
+
+/// Runs a command made up of an opcode, an optional 24-bit address, `dummy`
+/// dummy bytes, and a data phase.
+///
+/// This is the framing shared by almost all 25-series commands. `data` is
+/// overwritten with the bytes received while it is sent. Like with
+/// [`spi_command`], the chip is deselected even if a transfer fails.
+pub fn framed_command<SPI: Transfer<u8>, CS: OutputPin>(
+    spi: &mut SPI,
+    cs: &mut CS,
+    cs_delay: Option<CsDelay<'_>>,
+    opcode: u8,
+    addr: Option<u32>,
+    dummy: u8,
+    data: &mut [u8],
+) -> Result<(), Error<SPI, CS>> {
+    let mut header = [opcode, 0, 0, 0];
+    let header_len = match addr {
+        Some(addr) => {
+            header[1..].copy_from_slice(&addr.to_be_bytes()[1..]);
+            4
+        }
+        None => 1,
+    };
+
+    transaction(spi, cs, cs_delay, |spi| {
+        spi.transfer(&mut header[..header_len])?;
+        let mut left = usize::from(dummy);
+        while left > 0 {
+            let mut zeros = [0; 8];
+            let n = left.min(zeros.len());
+            spi.transfer(&mut zeros[..n])?;
+            left -= n;
+        }
+        if !data.is_empty() {
+            spi.transfer(data)?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn test_framed_command() {
+        let (chip, mut spi, mut cs) = mock::chip(0x100000);
+        chip.borrow_mut().memory[0x012345] = 0x67;
+
+        let mut data = [0; 1];
+        framed_command(&mut spi, &mut cs, None, 0x03, Some(0x012345), 0, &mut data).unwrap();
+        assert_eq!(data, [0x67]);
+        framed_command(&mut spi, &mut cs, None, 0x0B, Some(0xAB_CDEF), 10, &mut []).unwrap();
+        framed_command(&mut spi, &mut cs, None, 0x06, None, 0, &mut []).unwrap();
+
+        let transactions = &chip.borrow().transactions;
+        assert_eq!(transactions[0], [0x03, 0x01, 0x23, 0x45, 0x00]);
+        assert_eq!(transactions[1][..4], [0x0B, 0xAB, 0xCD, 0xEF]);
+        assert_eq!(transactions[1][4..], [0; 10]);
+        assert_eq!(transactions[2], [0x06]);
+    }
+
+    #[test]
+    fn test_context_passes_error_on() {
+        let result: Result<(), Error<mock::Spi, mock::Cs>> = Err(Error::OutOfBounds);
+        match result.context("test", 0x100) {
+            Err(Error::OutOfBounds) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_spi_command_phases() {
+        let (chip, mut spi, mut cs) = mock::chip(0x100);
+        chip.borrow_mut().memory[0x12] = 0x34;
+
+        let mut header = [0x03, 0x00, 0x00, 0x12];
+        let mut data = [0; 1];
+        spi_command(
+            &mut spi,
+            &mut cs,
+            None,
+            &mut [&mut header, &mut [], &mut data],
+        )
+        .unwrap();
+        assert_eq!(data, [0x34]);
+        assert_eq!(
+            chip.borrow().transactions,
+            [vec![0x03, 0x00, 0x00, 0x12, 0x00]]
+        );
+    }
+
+    #[test]
+    fn test_cs_delay() {
+        struct Log(Vec<u32>);
+
+        impl Delay for Log {
+            fn delay_us(&mut self, us: u32) {
+                self.0.push(us);
+            }
+        }
+
+        let (chip, mut spi, mut cs) = mock::chip(0x100);
+        let mut log = Log(Vec::new());
+        let cs_delay = CsDelay {
+            delay: &mut log,
+            setup_us: 2,
+            hold_us: 3,
+        };
+        framed_command(&mut spi, &mut cs, Some(cs_delay), 0x06, None, 0, &mut []).unwrap();
+        assert_eq!(log.0, [2, 3]);
+        assert_eq!(chip.borrow().transactions, [vec![0x06]]);
+    }
+}
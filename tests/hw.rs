@@ -0,0 +1,265 @@
+//! Hardware-in-the-loop tests against real chips on the dev board.
+//!
+//! Only built with the `hw-tests` Cargo feature. The chips to test are taken
+//! from the `SPI_MEMORY_HW_CHIPS` environment variable, a comma-separated
+//! list of `<spidev path>:<CS GPIO number>` entries, eg.
+//!
+//! ```text
+//! SPI_MEMORY_HW_CHIPS=/dev/spidev0.0:8,/dev/spidev0.0:7 cargo test --features hw-tests
+//! ```
+//!
+//! Every chip runs the same checks, and a compatibility report with one row
+//! per chip is printed at the end (use `--nocapture` to see it). The checks
+//! erase and program the last sector of every chip. Without the variable,
+//! nothing is tested.
+
+#![cfg(feature = "hw-tests")]
+
+use spi_memory::linux::SpidevFlash;
+use spi_memory::prelude::*;
+use spi_memory::series25::{Capabilities, Protection};
+use spi_memory::Error;
+use std::env;
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+
+/// SPI clock used for all chips.
+const HZ: u32 = 1_000_000;
+
+/// Outcome of one check on one chip.
+enum Outcome {
+    Passed,
+    Failed(String),
+    /// The chip doesn't have the tested feature.
+    Unsupported,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Passed => f.write_str("ok"),
+            Outcome::Failed(_) => f.write_str("FAIL"),
+            Outcome::Unsupported => f.write_str("n/a"),
+        }
+    }
+}
+
+impl<T, E: fmt::Debug> From<Result<T, E>> for Outcome {
+    fn from(result: Result<T, E>) -> Self {
+        match result {
+            Ok(_) => Outcome::Passed,
+            Err(e) => Outcome::Failed(format!("{:?}", e)),
+        }
+    }
+}
+
+/// Results of all checks on one chip.
+struct Report {
+    chip: String,
+    id: String,
+    capacity: Option<u32>,
+    checks: Vec<(&'static str, Outcome)>,
+}
+
+type FlashError = Error<linux_embedded_hal::Spidev, linux_embedded_hal::SysfsPin>;
+
+fn fail(msg: impl Into<String>) -> Outcome {
+    Outcome::Failed(msg.into())
+}
+
+fn read_write(flash: &mut SpidevFlash, sector: u32) -> Outcome {
+    let result = (|| -> Result<Outcome, FlashError> {
+        flash.erase_sectors(sector, 1)?;
+        let mut data: Vec<u8> = (0..=255).collect();
+        flash.write_bytes(sector + 0x80, &mut data)?;
+        let mut buf = [0; 256];
+        flash.read(sector + 0x80, &mut buf)?;
+        Ok(if buf.iter().copied().eq(0..=255) {
+            Outcome::Passed
+        } else {
+            fail("read back data differs")
+        })
+    })();
+    result.unwrap_or_else(|e| fail(format!("{:?}", e)))
+}
+
+fn erase(flash: &mut SpidevFlash, sector: u32) -> Outcome {
+    let result = (|| -> Result<Outcome, FlashError> {
+        flash.write_bytes(sector, &mut [0; 16])?;
+        flash.erase_sectors(sector, 1)?;
+        let mut buf = vec![0; flash.sector_size() as usize];
+        flash.read(sector, &mut buf)?;
+        Ok(if buf.iter().all(|&b| b == 0xFF) {
+            Outcome::Passed
+        } else {
+            fail("sector not erased")
+        })
+    })();
+    result.unwrap_or_else(|e| fail(format!("{:?}", e)))
+}
+
+fn protection(flash: &mut SpidevFlash, sector: u32) -> Outcome {
+    let capacity = match flash.capacity() {
+        Some(capacity) => capacity,
+        None => return Outcome::Unsupported,
+    };
+    let result = (|| -> Result<Outcome, FlashError> {
+        flash.set_protection(Protection::top(capacity, capacity - sector))?;
+        flash.set_check_protection(true);
+        let driver_refused = matches!(flash.erase_sectors(sector, 1), Err(Error::Protected { .. }));
+
+        // Make sure the chip itself ignores the erase, too.
+        flash.set_check_protection(false);
+        flash.write_bytes(sector, &mut [0])?;
+        let mut byte = [0];
+        flash.read(sector, &mut byte)?;
+        flash.set_protection(Protection::NONE)?;
+
+        Ok(if !driver_refused {
+            fail("driver allowed erasing a protected sector")
+        } else if byte[0] != 0xFF {
+            fail("chip programmed a protected sector")
+        } else {
+            Outcome::Passed
+        })
+    })();
+    result.unwrap_or_else(|e| fail(format!("{:?}", e)))
+}
+
+fn power_down(mut flash: SpidevFlash) -> Outcome {
+    let result = (|| -> Result<Outcome, FlashError> {
+        let id = flash.read_jedec_id()?;
+        let powered_down = flash.power_down().map_err(|(_, e)| e)?;
+        let (mut flash, _) = powered_down.release_power_down().map_err(|(_, e)| e)?;
+        thread::sleep(Duration::from_millis(1));
+        let woken = flash.read_jedec_id()?;
+        Ok(
+            if woken.mfr_code() == id.mfr_code() && woken.device_id() == id.device_id() {
+                Outcome::Passed
+            } else {
+                fail(format!("JEDEC ID after wake-up: {:?}", woken))
+            },
+        )
+    })();
+    result.unwrap_or_else(|e| fail(format!("{:?}", e)))
+}
+
+fn suspend(flash: &mut SpidevFlash, caps: Capabilities) -> Outcome {
+    if !caps.contains(Capabilities::SUSPEND) {
+        return Outcome::Unsupported;
+    }
+    match flash.is_suspended() {
+        Ok(false) => Outcome::Passed,
+        Ok(true) => fail("idle chip reports a suspended operation"),
+        Err(e) => fail(format!("{:?}", e)),
+    }
+}
+
+fn test_chip(path: &str, cs: u64) -> Report {
+    let mut report = Report {
+        chip: format!("{}:{}", path, cs),
+        id: String::from("-"),
+        capacity: None,
+        checks: Vec::new(),
+    };
+    let mut flash = match SpidevFlash::open_spidev(path, cs, HZ) {
+        Ok(flash) => flash,
+        Err(e) => {
+            report.checks.push(("init", fail(e.to_string())));
+            return report;
+        }
+    };
+    report.checks.push(("init", Outcome::Passed));
+
+    match flash.read_jedec_id() {
+        Ok(id) => report.id = format!("{:?}", id),
+        Err(e) => report.checks.push(("id", fail(format!("{:?}", e)))),
+    }
+    let geometry = match flash.detect_geometry() {
+        Ok(Some(_)) => Outcome::Passed,
+        Ok(None) => match flash.detect_mirroring() {
+            Ok(capacity) => {
+                flash.set_capacity(capacity);
+                Outcome::Unsupported
+            }
+            Err(e) => fail(format!("{:?}", e)),
+        },
+        Err(e) => fail(format!("{:?}", e)),
+    };
+    report.checks.push(("geometry", geometry));
+    report.capacity = flash.capacity();
+    let caps = flash
+        .capabilities()
+        .unwrap_or_else(|_| Capabilities::empty());
+
+    let sector = match report.capacity {
+        Some(capacity) => capacity - flash.sector_size(),
+        None => return report,
+    };
+    report
+        .checks
+        .push(("read/write", read_write(&mut flash, sector)));
+    report.checks.push(("erase", erase(&mut flash, sector)));
+    report
+        .checks
+        .push(("self test", flash.self_test(sector).into()));
+    report
+        .checks
+        .push(("protection", protection(&mut flash, sector)));
+    report.checks.push(("suspend", suspend(&mut flash, caps)));
+    report.checks.push(("power-down", power_down(flash)));
+    report
+}
+
+#[test]
+fn chip_matrix() {
+    let chips = match env::var("SPI_MEMORY_HW_CHIPS") {
+        Ok(chips) => chips,
+        Err(_) => {
+            eprintln!("SPI_MEMORY_HW_CHIPS not set, no chips to test");
+            return;
+        }
+    };
+
+    let reports: Vec<Report> = chips
+        .split(',')
+        .map(|entry| {
+            let (path, cs) = entry
+                .rsplit_once(':')
+                .expect("expected `<spidev path>:<CS GPIO>`");
+            test_chip(path, cs.parse().expect("invalid CS GPIO number"))
+        })
+        .collect();
+
+    println!("| Chip | JEDEC ID | Capacity | Checks |");
+    println!("|------|----------|----------|--------|");
+    for report in &reports {
+        let checks: Vec<String> = report
+            .checks
+            .iter()
+            .map(|(name, outcome)| format!("{}: {}", name, outcome))
+            .collect();
+        let capacity = report
+            .capacity
+            .map_or_else(|| String::from("?"), |c| format!("{} KiB", c / 1024));
+        println!(
+            "| {} | {} | {} | {} |",
+            report.chip,
+            report.id,
+            capacity,
+            checks.join(", ")
+        );
+    }
+
+    let mut failed = false;
+    for report in &reports {
+        for (name, outcome) in &report.checks {
+            if let Outcome::Failed(msg) = outcome {
+                eprintln!("{}: {} failed: {}", report.chip, name, msg);
+                failed = true;
+            }
+        }
+    }
+    assert!(!failed, "some checks failed");
+}